@@ -3,17 +3,10 @@
 -- These packages are built-in to the Tiny SSE server
 local uuid = require "uuid"
 local json = require "json"
-local http = require "http"
-local agent = http.agent {
-    headers = {
-        ["content-type"] = "application/json"
-    }
-}
+local server = require "server"
 
 local function pubmsg(msg)
-    agent:post("http://127.0.0.1:1983/sse", {
-        body = json(msg)
-    })
+    server.publish(msg)
 end
 
 function subscribe(sub)
@@ -1,30 +1,28 @@
 -- Example of publishing occupancy messages at regular intervals
 
 -- These packages are built-in to the Tiny SSE server
-local json = require "json"
-local http = require "http"
-local agent = http.agent {
-    headers = {
-        ["content-type"] = "application/json"
-    }
-}
+local server = require "server"
+
+-- The worker the script runs in, with --script-workers
+local worker = 1
 
--- The number of active subscribers
-local subs = 0
+-- The number of active subscribers, as listed by the server
+local function occupancy()
+    return tostring(#server.subscribers())
+end
 
-local function pubmsg(msg)
-    agent:post("http://127.0.0.1:1983/sse", {
-        body = json(msg)
-    })
+function startup(cli)
+    worker = cli.script_worker
 end
 
 function tick(count)
     -- By default the script tick runs every 500ms.
-    -- Publish occupancy messages every 10 seconds.
-    if count % 20 == 0 then
-        pubmsg {
+    -- Publish occupancy messages every 10 seconds, from the first worker
+    -- only since every worker runs the tick.
+    if worker == 1 and count % 20 == 0 then
+        server.publish {
             event = "occupancy",
-            data = tostring(subs)
+            data = occupancy()
         }
     end
 end
@@ -34,16 +32,7 @@ function catchup(sub, last_event_id)
     return {
         {
             event = "occupancy",
-            data = tostring(subs)
+            data = occupancy()
         }
     }
 end
-
-function subscribe(sub)
-    subs = subs + 1
-    return sub
-end
-
-function unsubscribe(sub)
-    subs = subs - 1
-end
@@ -0,0 +1,192 @@
+use std::{collections::BTreeMap, fmt, str::FromStr, time::Duration};
+
+use axum::{
+    Json, Router,
+    extract::{Request, State},
+    http::header,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use axum_extra::extract::Query;
+use serde_json::json;
+
+use crate::{
+    error::AppError,
+    req::SubReq,
+    state::{AppState, Control},
+};
+
+/// The token that authorizes requests to the admin API, given as a bearer
+/// token in their `Authorization` header.
+///
+/// The token is not shown by `Debug` so that it is kept out of the logs.
+#[derive(Clone)]
+pub struct AdminToken(String);
+
+impl AdminToken {
+    /// Whether a token is this one, compared in constant time so that the
+    /// response time does not leak how much of it matched.
+    fn verify(&self, token: &str) -> bool {
+        let (expected, token) = (self.0.as_bytes(), token.as_bytes());
+
+        expected.len() == token.len()
+            && expected
+                .iter()
+                .zip(token)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl FromStr for AdminToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            Err("the admin token must not be empty".to_string())
+        } else {
+            Ok(Self(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AdminToken(..)")
+    }
+}
+
+/// Builds the router of the admin API, which is nested under the
+/// `--admin-path` of the internal router.
+pub fn router(token: AdminToken) -> Router<AppState> {
+    Router::new()
+        .route("/subscribers", get(subscribers).delete(disconnect))
+        .route("/subscribers/count", get(count))
+        .route("/queue", get(queue))
+        .layer(middleware::from_fn_with_state(token, authorize))
+}
+
+/// Rejects the requests without the admin token.
+async fn authorize(
+    State(token): State<AdminToken>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| token.verify(value.trim()));
+
+    if authorized {
+        Ok(next.run(req).await)
+    } else {
+        Err(AppError::Unauthorized("invalid admin token".into()))
+    }
+}
+
+/// The subscribers to list or disconnect, by their `sub.id` or IP address.
+#[derive(Debug, serde::Deserialize)]
+struct SubscriberQuery {
+    id: Option<String>,
+    ip: Option<String>,
+    /// The retry delay in milliseconds for the disconnected subscribers
+    retry: Option<u64>,
+}
+
+impl SubscriberQuery {
+    fn matches(&self, sub_req: &SubReq) -> bool {
+        self.id.as_ref().is_none_or(|id| sub_req.id() == id)
+            && self
+                .ip
+                .as_ref()
+                .is_none_or(|ip| sub_req.req().addr().ip() == ip)
+    }
+}
+
+/// Responds with the connected subscribers, with the fields the script set
+/// on their subscribe requests and the credentials in their headers
+/// redacted.
+async fn subscribers(
+    State(state): State<AppState>,
+    Query(query): Query<SubscriberQuery>,
+) -> impl IntoResponse {
+    let subscribers: Vec<_> = state
+        .subscribers
+        .list()
+        .into_iter()
+        .filter(|subscriber| query.matches(&subscriber.sub_req))
+        .map(|subscriber| {
+            json!({
+                "id": subscriber.sub_req.id(),
+                "req": subscriber.sub_req.req().redacted(),
+                "topics": subscriber.sub_req.topics(),
+                "meta": subscriber.meta,
+                "connected_at": subscriber.connected_at,
+                "messages": subscriber.messages,
+                "bytes": subscriber.bytes,
+            })
+        })
+        .collect();
+
+    Json(json!({ "subscribers": subscribers }))
+}
+
+/// Responds with the number of connected subscribers, in total and by path
+/// and topic.
+async fn count(State(state): State<AppState>) -> impl IntoResponse {
+    let subscribers = state.subscribers.list();
+    let mut paths = BTreeMap::<&str, usize>::new();
+    let mut topics = BTreeMap::<&str, usize>::new();
+
+    for subscriber in &subscribers {
+        *paths.entry(subscriber.sub_req.req().path()).or_default() += 1;
+
+        for topic in subscriber.sub_req.topics() {
+            *topics.entry(topic).or_default() += 1;
+        }
+    }
+
+    Json(json!({
+        "subscribers": subscribers.len(),
+        "paths": paths,
+        "topics": topics,
+    }))
+}
+
+/// Disconnects the subscribers with an id or IP address, which then
+/// reconnect after the `retry` delay or `--timeout-retry`.
+async fn disconnect(
+    State(state): State<AppState>,
+    Query(query): Query<SubscriberQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    if query.id.is_none() && query.ip.is_none() {
+        return Err(AppError::BadRequest(
+            "expected the id or ip of the subscribers to disconnect".into(),
+        ));
+    }
+
+    let retry = query
+        .retry
+        .map(Duration::from_millis)
+        .unwrap_or(state.timeout_retry);
+
+    let disconnected = state
+        .subscribers
+        .control_where(|sub_req| query.matches(sub_req), Control::Disconnect(retry));
+
+    Ok(Json(json!({ "disconnected": disconnected })))
+}
+
+/// Responds with the number of messages queued for the subscribers without
+/// topics and for each topic, which are dropped for lagging subscribers
+/// once they reach the capacity.
+async fn queue(State(state): State<AppState>) -> impl IntoResponse {
+    Json(json!({
+        "capacity": state.topics.capacity(),
+        "broadcast": state.broadcast.len(),
+        "topics": state.topics.lens().into_iter().collect::<BTreeMap<_, _>>(),
+    }))
+}
@@ -1,9 +1,11 @@
 use bytesize::ByteSize;
-use clap::Parser;
+use clap::{CommandFactory as _, Parser, ValueEnum, error::ErrorKind};
 use http::{HeaderName, HeaderValue, Method};
 use humantime::parse_duration;
 use mlua::LuaSerdeExt;
-use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use std::{ffi::OsString, fmt, net::SocketAddr, num::NonZeroUsize, path::PathBuf, time::Duration};
+
+use crate::{admin::AdminToken, config::Config, script::HOOKS};
 use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin};
 
 /// Tiny SSE
@@ -12,15 +14,67 @@ use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin};
 #[derive(Debug, Clone, Parser)]
 #[command(version)]
 pub struct Cli {
+    #[clap(
+        long,
+        value_name = "FILE_PATH",
+        env = "TINYSSE_CONFIG",
+        help = "Load the options from a TOML configuration file. \
+                Options given on the command line or by environment variables take precedence over the file"
+    )]
+    pub config: Option<PathBuf>,
+
     #[clap(
         short,
         long,
         value_name = "ADDR:PORT",
         default_value = "127.0.0.1:1983",
+        value_parser = parse_listen,
+        value_delimiter = ',',
         env = "TINYSSE_LISTEN",
-        help = "The address and port for the HTTP server to listen"
+        help = "The address and port for the HTTP server to listen, or the path of a Unix domain socket prefixed with `unix:` (e.g., unix:/run/tinysse.sock).\n\
+                Can be given multiple times to listen on several addresses, such as both IPv4 and IPv6"
+    )]
+    pub listen: Vec<Listen>,
+
+    #[clap(
+        long,
+        value_name = "ADDR:PORT",
+        value_parser = parse_listen,
+        value_delimiter = ',',
+        env = "TINYSSE_INTERNAL_LISTEN",
+        help = "Serve the publish route on a separate internal listener, at an address and port or a Unix domain socket prefixed with `unix:`.\n\
+                The `--listen` addresses then only serve subscribing and static files. Can be given multiple times"
+    )]
+    pub internal_listen: Vec<Listen>,
+
+    #[clap(
+        long,
+        value_name = "MODE",
+        value_parser = parse_unix_socket_mode,
+        env = "TINYSSE_UNIX_SOCKET_MODE",
+        help = "The file permissions of the Unix domain sockets given by `--listen` and `--internal-listen` as an octal mode (e.g., 660, 0666).\n\
+                By default the permissions are determined by the process umask"
+    )]
+    pub unix_socket_mode: Option<u32>,
+
+    #[clap(
+        long,
+        value_name = "FILE_PATH",
+        env = "TINYSSE_TLS_CERT",
+        requires = "tls_key",
+        help = "Serve HTTPS (with HTTP/2) using the PEM-encoded certificate chain at the specified path.\n\
+                The certificate and key are reloaded without a restart when they change on disk"
     )]
-    pub listen: SocketAddr,
+    pub tls_cert: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "FILE_PATH",
+        env = "TINYSSE_TLS_KEY",
+        requires = "tls_cert",
+        help = "The path to the PEM-encoded private key for the certificate given by `--tls-cert`"
+    )]
+    pub tls_key: Option<PathBuf>,
 
     #[clap(
         short = 'L',
@@ -40,7 +94,7 @@ pub struct Cli {
         value_parser = parse_duration,
         env = "TINYSSE_KEEP_ALIVE",
         help = "The interval between keep-alive messages sent to clients (e.g., 60s, 2m).\n\
-                Keep-alive messages are sent periodically to ensure that clients remain connected"
+                Keep-alive messages are sent periodically to ensure that clients remain connected. 0 disables them"
     )]
     pub keep_alive: Duration,
 
@@ -79,6 +133,48 @@ pub struct Cli {
     )]
     pub timeout_retry: Duration,
 
+    #[clap(
+        long,
+        value_name = "TIMEOUT",
+        default_value = "30s",
+        value_parser = parse_duration,
+        env = "TINYSSE_POLL_TIMEOUT",
+        help = "The maximum duration a long-poll subscribe request waits for a message (e.g., 30s, 1m).\n\
+                The request responds with an empty array of messages when it passes"
+    )]
+    pub poll_timeout: Duration,
+
+    #[clap(
+        long,
+        value_name = "TIMEOUT",
+        default_value = "10s",
+        value_parser = parse_duration,
+        env = "TINYSSE_SHUTDOWN_TIMEOUT",
+        help = "The maximum duration to wait for subscribers to disconnect and for the `unsubscribe` functions to finish when the server shuts down (e.g., 10s, 1m)"
+    )]
+    pub shutdown_timeout: Duration,
+
+    #[clap(
+        long,
+        value_name = "RETRY",
+        default_value = "1s",
+        value_parser = parse_duration,
+        env = "TINYSSE_SHUTDOWN_RETRY",
+        help = "The retry delay sent to subscribers when the server shuts down (e.g., 1s, 500ms)"
+    )]
+    pub shutdown_retry: Duration,
+
+    #[clap(
+        long,
+        value_name = "JITTER",
+        default_value = "5s",
+        value_parser = parse_duration,
+        env = "TINYSSE_SHUTDOWN_RETRY_JITTER",
+        help = "The maximum random delay added to `--shutdown-retry` for each subscriber (e.g., 5s, 0s).\n\
+                This spreads out the reconnects of the subscribers to the other servers or after a restart"
+    )]
+    pub shutdown_retry_jitter: Duration,
+
     #[clap(
         short = 'c',
         long,
@@ -89,6 +185,49 @@ pub struct Cli {
     )]
     pub capacity: usize,
 
+    #[clap(
+        long,
+        value_name = "POLICY",
+        default_value = "ignore",
+        env = "TINYSSE_LAG_POLICY",
+        help = "What to do when a subscriber falls behind by more than `--capacity` messages and misses some of them.\n\
+                `ignore` only logs it. `notify` sends the subscriber a `lagged` event with the number of missed messages.\n\
+                `catchup` catches-up the subscriber from the last message it received, or notifies it if it cannot be caught-up.\n\
+                `disconnect` disconnects the subscriber with the `--timeout-retry` delay"
+    )]
+    pub lag_policy: LagPolicy,
+
+    #[clap(
+        long,
+        value_name = "COUNT",
+        default_value = "0",
+        env = "TINYSSE_HISTORY_SIZE",
+        help = "The number of published messages to keep in memory for catching-up reconnecting subscribers from their Last-Event-ID.\n\
+                Setting this to 0 disables the history"
+    )]
+    pub history_size: usize,
+
+    #[clap(
+        long,
+        value_name = "DURATION",
+        default_value = "0s",
+        value_parser = parse_duration,
+        env = "TINYSSE_HISTORY_TTL",
+        help = "The maximum age of messages kept in the history (e.g., 5m, 1h).\n\
+                Setting this to 0s keeps messages until they are evicted by `--history-size`"
+    )]
+    pub history_ttl: Duration,
+
+    #[clap(
+        long,
+        value_name = "FILE_PATH",
+        env = "TINYSSE_HISTORY_DB",
+        requires = "history_size",
+        help = "Keep the message history in a SQLite database at the specified path instead of in memory, so that it survives restarts.\n\
+                The history is then bounded by `--history-size`, which must be given, and `--history-ttl`. A size of 0 disables it as well"
+    )]
+    pub history_db: Option<PathBuf>,
+
     #[clap(
         short = 's',
         long,
@@ -116,6 +255,112 @@ pub struct Cli {
     )]
     pub script_tick: Duration,
 
+    #[clap(
+        long,
+        env = "TINYSSE_SCRIPT_WATCH",
+        help = "Reload the Lua script whenever the file is modified. \
+                The script is also reloaded on a SIGHUP signal"
+    )]
+    pub script_watch: bool,
+
+    #[clap(
+        long,
+        value_name = "COUNT",
+        default_value = "1",
+        env = "TINYSSE_SCRIPT_WORKERS",
+        help = "The number of isolated Lua states (workers) that run the script. \
+                The functions are called on the workers in turn, so globals are not shared between workers \
+                but the `store` package is. Use more than one worker only if the script does not rely on shared globals"
+    )]
+    pub script_workers: NonZeroUsize,
+
+    #[clap(
+        long,
+        value_name = "[HOOK=]DURATION",
+        value_parser = parse_hook_timeout,
+        value_delimiter = ',',
+        env = "TINYSSE_HOOK_TIMEOUT",
+        help = "The maximum time the Lua script functions can run (e.g., 1s, 100ms), or a single function when prefixed with its name (e.g., message=10ms).\n\
+                A function that runs longer is interrupted, even in a loop, and fails as set by `--hook-timeout-policy`. \
+                A timeout of 0s does not limit the time. Can be given multiple times"
+    )]
+    pub hook_timeout: Vec<HookSetting<Duration>>,
+
+    #[clap(
+        long,
+        value_name = "[HOOK=]POLICY",
+        default_value = "closed",
+        value_parser = parse_hook_timeout_policy,
+        value_delimiter = ',',
+        env = "TINYSSE_HOOK_TIMEOUT_POLICY",
+        help = "What to do when a Lua script function times out, or a single function when prefixed with its name (e.g., message=open).\n\
+                `closed` fails the call as an error, rejecting the message or subscriber. \
+                `open` carries on as if the function was not defined. Can be given multiple times"
+    )]
+    pub hook_timeout_policy: Vec<HookSetting<TimeoutPolicy>>,
+
+    #[clap(
+        long,
+        value_name = "BYTES",
+        env = "TINYSSE_SCRIPT_MEMORY_LIMIT",
+        help = "The maximum memory the Lua script can allocate in each worker (e.g., 64MB, 1GB).\n\
+                Allocating more raises a `not enough memory` error in the script. By default the memory is not limited"
+    )]
+    pub script_memory_limit: Option<ByteSize>,
+
+    #[clap(
+        long,
+        env = "TINYSSE_SCRIPT_SANDBOX",
+        conflicts_with = "unsafe_script",
+        help = "Run the Lua script in a sandbox, where accessing files, HTTP hosts and environment variables and running commands \
+                raise an error unless they are allowed by the `--script-allow-*` options"
+    )]
+    pub script_sandbox: bool,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        value_delimiter = ',',
+        env = "TINYSSE_SCRIPT_ALLOW_PATH",
+        requires = "script_sandbox",
+        help = "Allow the sandboxed Lua script to read and write the files in the specified directory, or the specified file.\n\
+                This also applies to the modules loaded with `require`. Can be given multiple times"
+    )]
+    pub script_allow_path: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "HOST",
+        value_delimiter = ',',
+        env = "TINYSSE_SCRIPT_ALLOW_HOST",
+        requires = "script_sandbox",
+        help = "Allow the sandboxed Lua script to make HTTP requests to the specified host, or to its subdomains when prefixed with `*.` (e.g., api.example.com, *.example.com).\n\
+                Can be given multiple times"
+    )]
+    pub script_allow_host: Vec<String>,
+
+    #[clap(
+        long,
+        value_name = "NAME",
+        value_delimiter = ',',
+        env = "TINYSSE_SCRIPT_ALLOW_ENV",
+        requires = "script_sandbox",
+        help = "Allow the sandboxed Lua script to read the specified environment variable with `os.getenv`. Can be given multiple times"
+    )]
+    pub script_allow_env: Vec<String>,
+
+    #[clap(
+        long,
+        value_name = "PROGRAM",
+        value_delimiter = ',',
+        env = "TINYSSE_SCRIPT_ALLOW_EXEC",
+        requires = "script_sandbox",
+        help = "Allow the sandboxed Lua script to run the specified program with `os.execute` or `io.popen`.\n\
+                The commands cannot use shell operators such as `;`, `|` or `$`, but their arguments are not checked:\n\
+                an allowed program can access paths and hosts outside the other allow-lists. Can be given multiple times"
+    )]
+    pub script_allow_exec: Vec<String>,
+
     #[clap(
         long,
         env = "TINYSSE_UNSAFE_SCRIPT",
@@ -155,6 +400,37 @@ pub struct Cli {
     )]
     pub sub_path: String,
 
+    #[clap(
+        long,
+        value_name = "URL_PATH",
+        default_value = "/metrics",
+        env = "TINYSSE_METRICS_PATH",
+        help = "The URL path for the Prometheus metrics of the server.\n\
+                The metrics are served on the `--internal-listen` addresses if they are given, \
+                otherwise they are public on the `--listen` addresses, without authentication"
+    )]
+    pub metrics_path: String,
+
+    #[clap(
+        long,
+        value_name = "URL_PATH",
+        default_value = "/admin",
+        env = "TINYSSE_ADMIN_PATH",
+        value_parser = parse_admin_path,
+        help = "The URL path prefix of the admin API, which is only served if `--admin-token` is given.\n\
+                The admin API is served on the `--internal-listen` addresses if they are given"
+    )]
+    pub admin_path: String,
+
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "TINYSSE_ADMIN_TOKEN",
+        hide_env_values = true,
+        help = "Serve the admin API for listing and disconnecting subscribers, for requests with the `Authorization: Bearer <TOKEN>` header"
+    )]
+    pub admin_token: Option<AdminToken>,
+
     #[clap(
         short = 'D',
         long,
@@ -220,19 +496,122 @@ pub struct Cli {
         help = "Set Access-Control-Max-Age header to the specified duration (e.g., 1h, 60s)"
     )]
     pub cors_max_age: Duration,
+
+    /// The settings of the Lua script from the `[script]` table of the
+    /// configuration file.
+    #[clap(skip)]
+    pub script_settings: Option<toml::Table>,
+}
+
+impl Cli {
+    /// Parses the command line arguments and environment variables, with the
+    /// options of the `--config` file for those that are not given.
+    ///
+    /// Exits with an error message if the options are invalid, as
+    /// `Cli::parse()` does.
+    pub fn load() -> Self {
+        Self::try_load_from(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Parses the given arguments as `Cli::load()` does, returning the error
+    /// if the options are invalid.
+    pub fn try_load_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+        let mut command = Cli::command();
+
+        // The arguments are only validated along with the options of the file,
+        // since an option given on the command line may require one of them
+        let Ok(matches) = command
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(&args)
+        else {
+            return Cli::try_parse_from(args);
+        };
+        let Some(path) = matches.get_one::<PathBuf>("config") else {
+            return Cli::try_parse_from(args);
+        };
+
+        let config = Config::load(path, &command, &matches)
+            .map_err(|e| command.error(ErrorKind::InvalidValue, format!("{e:#}")))?;
+
+        let mut cli = Cli::try_parse_from(args.into_iter().chain(config.args))?;
+        cli.script_settings = config.script;
+        Ok(cli)
+    }
 }
 
 impl mlua::IntoLua for Cli {
     fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
         let tbl = lua.create_table()?;
 
-        tbl.set("listen", self.listen.to_string())?;
+        tbl.set(
+            "config",
+            self.config
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+        )?;
+        tbl.set(
+            "listen",
+            self.listen
+                .iter()
+                .map(|listen| listen.to_string())
+                .collect::<Vec<_>>(),
+        )?;
+        tbl.set(
+            "internal_listen",
+            self.internal_listen
+                .iter()
+                .map(|listen| listen.to_string())
+                .collect::<Vec<_>>(),
+        )?;
+        tbl.set(
+            "unix_socket_mode",
+            self.unix_socket_mode.map(|mode| format!("{mode:o}")),
+        )?;
+        tbl.set(
+            "tls_cert",
+            self.tls_cert
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+        )?;
+        tbl.set(
+            "tls_key",
+            self.tls_key
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+        )?;
         tbl.set("log_level", self.log_level.to_string())?;
         tbl.set("keep_alive", self.keep_alive.as_millis())?;
         tbl.set("keep_alive_text", self.keep_alive_text)?;
         tbl.set("timeout", self.timeout.as_millis())?;
         tbl.set("timeout_retry", self.timeout_retry.as_millis())?;
+        tbl.set("poll_timeout", self.poll_timeout.as_millis())?;
+        tbl.set("shutdown_timeout", self.shutdown_timeout.as_millis())?;
+        tbl.set("shutdown_retry", self.shutdown_retry.as_millis())?;
+        tbl.set(
+            "shutdown_retry_jitter",
+            self.shutdown_retry_jitter.as_millis(),
+        )?;
         tbl.set("capacity", self.capacity)?;
+        tbl.set(
+            "lag_policy",
+            self.lag_policy
+                .to_possible_value()
+                .map(|v| v.get_name().to_string()),
+        )?;
+        tbl.set("history_size", self.history_size)?;
+        tbl.set("history_ttl", self.history_ttl.as_millis())?;
+        tbl.set(
+            "history_db",
+            self.history_db
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+        )?;
         tbl.set(
             "script",
             self.script
@@ -240,10 +619,49 @@ impl mlua::IntoLua for Cli {
                 .map(|p| p.to_string_lossy().into_owned()),
         )?;
         tbl.set("script_tick", self.script_tick.as_millis())?;
+        tbl.set("script_watch", self.script_watch)?;
+        tbl.set("script_workers", self.script_workers.get())?;
         tbl.set("script_data", self.script_data)?;
+        tbl.set("script_settings", lua.to_value(&self.script_settings)?)?;
+        tbl.set(
+            "hook_timeout",
+            self.hook_timeout
+                .iter()
+                .map(|timeout| timeout.to_arg(humantime::format_duration(timeout.value)))
+                .collect::<Vec<_>>(),
+        )?;
+        tbl.set(
+            "hook_timeout_policy",
+            self.hook_timeout_policy
+                .iter()
+                .filter_map(|policy| {
+                    let value = policy.value.to_possible_value()?;
+                    Some(policy.to_arg(value.get_name()))
+                })
+                .collect::<Vec<_>>(),
+        )?;
+        tbl.set(
+            "script_memory_limit",
+            self.script_memory_limit.map(|limit| limit.as_u64()),
+        )?;
+        tbl.set("script_sandbox", self.script_sandbox)?;
+        tbl.set(
+            "script_allow_path",
+            self.script_allow_path
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect::<Vec<_>>(),
+        )?;
+        tbl.set("script_allow_host", self.script_allow_host)?;
+        tbl.set("script_allow_env", self.script_allow_env)?;
+        tbl.set("script_allow_exec", self.script_allow_exec)?;
         tbl.set("unsafe_script", self.unsafe_script)?;
         tbl.set("pub_path", self.pub_path)?;
         tbl.set("sub_path", self.sub_path)?;
+        tbl.set("metrics_path", self.metrics_path)?;
+        tbl.set("admin_path", self.admin_path)?;
+        // The admin token is kept from the script
+        tbl.set("admin_token", self.admin_token.is_some())?;
         tbl.set(
             "serve_static_dir",
             self.serve_static_dir
@@ -256,6 +674,131 @@ impl mlua::IntoLua for Cli {
     }
 }
 
+/// What to do when a subscriber falls behind the message queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LagPolicy {
+    Ignore,
+    Notify,
+    Catchup,
+    Disconnect,
+}
+
+/// What to do when a Lua script function times out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TimeoutPolicy {
+    /// Carry on as if the function was not defined.
+    Open,
+    /// Fail the call as an error.
+    #[default]
+    Closed,
+}
+
+/// An option for all the Lua script functions, or for a single function when
+/// given as `HOOK=VALUE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookSetting<T> {
+    pub hook: Option<String>,
+    pub value: T,
+}
+
+impl<T> HookSetting<T> {
+    /// The option as given on the command line, with the formatted value.
+    fn to_arg(&self, value: impl fmt::Display) -> String {
+        match &self.hook {
+            Some(hook) => format!("{hook}={value}"),
+            None => value.to_string(),
+        }
+    }
+}
+
+/// An address for the HTTP server to listen on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{addr}"),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn parse_listen(s: &str) -> anyhow::Result<Listen> {
+    match s.trim().strip_prefix("unix:") {
+        Some("") => anyhow::bail!("missing Unix domain socket path"),
+        Some(path) => Ok(Listen::Unix(PathBuf::from(path))),
+        None => Ok(Listen::Tcp(s.trim().parse()?)),
+    }
+}
+
+fn parse_unix_socket_mode(s: &str) -> anyhow::Result<u32> {
+    let mode = u32::from_str_radix(s.trim(), 8)?;
+
+    if mode > 0o7777 {
+        anyhow::bail!("invalid file mode {s}");
+    }
+
+    Ok(mode)
+}
+
+fn parse_admin_path(s: &str) -> anyhow::Result<String> {
+    let path = s.trim();
+
+    if !path.starts_with('/') {
+        anyhow::bail!("the admin path must start with a `/`");
+    }
+    if path.trim_end_matches('/').is_empty() {
+        anyhow::bail!("the admin path cannot be the root path `/`");
+    }
+    if path.contains('*') {
+        anyhow::bail!("the admin path cannot contain a wildcard `*`");
+    }
+
+    Ok(path.to_string())
+}
+
+fn parse_hook_setting<T>(
+    s: &str,
+    parse: impl FnOnce(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<HookSetting<T>> {
+    match s.trim().split_once('=') {
+        Some((hook, value)) => {
+            let hook = hook.trim();
+
+            if !HOOKS.contains(&hook) {
+                anyhow::bail!(
+                    "unknown function {hook:?}, expected one of {}",
+                    HOOKS.join(", ")
+                );
+            }
+
+            Ok(HookSetting {
+                hook: Some(hook.to_string()),
+                value: parse(value.trim())?,
+            })
+        }
+        None => Ok(HookSetting {
+            hook: None,
+            value: parse(s.trim())?,
+        }),
+    }
+}
+
+fn parse_hook_timeout(s: &str) -> anyhow::Result<HookSetting<Duration>> {
+    parse_hook_setting(s, |s| Ok(parse_duration(s)?))
+}
+
+fn parse_hook_timeout_policy(s: &str) -> anyhow::Result<HookSetting<TimeoutPolicy>> {
+    parse_hook_setting(s, |s| {
+        TimeoutPolicy::from_str(s, true)
+            .map_err(|_| anyhow::anyhow!("invalid policy {s:?}, expected open or closed"))
+    })
+}
+
 fn parse_allow_origin(s: &str) -> anyhow::Result<AllowOrigin> {
     if s.trim() == "*" {
         Ok(AllowOrigin::any())
@@ -0,0 +1,249 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::Context as _;
+use clap::{ArgAction, ArgMatches, Command, parser::ValueSource};
+
+/// The options of a TOML configuration file given by `--config`.
+///
+/// The keys are the names of the options, as in the `cli` table of the
+/// `startup(cli)` function, with values of the same format as on the
+/// command line.  Options that take several values can be given an array.
+///
+/// ```toml
+/// listen = ["127.0.0.1:1983", "[::1]:1983"]
+/// keep_alive = "30s"
+///
+/// [script]
+/// path = "script.lua"
+/// api_url = "https://example.com/api"
+/// ```
+///
+/// The `[script]` table holds the settings of the Lua script, except for its
+/// `path` which sets the `--script` option.  The relative paths of the
+/// options in `PATHS` are relative to the directory of the configuration
+/// file.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// The options as command line arguments.
+    pub args: Vec<OsString>,
+    /// The settings of the Lua script.
+    pub script: Option<toml::Table>,
+}
+
+/// The options that take the path of a file or directory.
+const PATHS: &[&str] = &[
+    "script",
+    "tls_cert",
+    "tls_key",
+    "history_db",
+    "serve_static_dir",
+    "script_allow_path",
+];
+
+impl Config {
+    /// Loads the options of a configuration file that are not given on the
+    /// command line or by environment variables, so that those take
+    /// precedence.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        command: &Command,
+        matches: &ArgMatches,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut table = fs::read_to_string(path)
+            .with_context(|| format!("read config {}", path.display()))?
+            .parse::<toml::Table>()
+            .with_context(|| format!("parse config {}", path.display()))?;
+
+        let script = match table.remove("script") {
+            Some(toml::Value::Table(mut script)) => {
+                if let Some(script_path) = script.remove("path") {
+                    table.insert("script".to_string(), script_path);
+                }
+                Some(script)
+            }
+            Some(script_path) => {
+                table.insert("script".to_string(), script_path);
+                None
+            }
+            None => None,
+        };
+
+        let mut args = Vec::new();
+
+        for (key, value) in table {
+            let id = key.replace('-', "_");
+            let arg = command
+                .get_arguments()
+                .find(|arg| {
+                    arg.get_id() == id.as_str()
+                        && id != "config"
+                        && !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+                })
+                .with_context(|| format!("unknown option {key:?} in config {}", path.display()))?;
+            let long = arg.get_long().expect("option has a long name");
+
+            if matches!(
+                matches.value_source(&id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                continue;
+            }
+
+            let values = match value {
+                toml::Value::Array(values) => values,
+                value => vec![value],
+            };
+            let values = if PATHS.contains(&id.as_str()) {
+                values
+                    .into_iter()
+                    .map(|value| relative_to(path, value))
+                    .collect()
+            } else {
+                values
+            };
+
+            for value in values {
+                let value = match value {
+                    // Flags do not take a value
+                    toml::Value::Boolean(enabled) if !arg.get_action().takes_values() => {
+                        if enabled {
+                            args.push(format!("--{long}").into());
+                        }
+                        continue;
+                    }
+                    toml::Value::String(s) => s,
+                    toml::Value::Integer(i) => i.to_string(),
+                    toml::Value::Float(f) => f.to_string(),
+                    toml::Value::Boolean(b) => b.to_string(),
+                    _ => anyhow::bail!(
+                        "invalid value for option {key:?} in config {}",
+                        path.display()
+                    ),
+                };
+
+                args.push(format!("--{long}={value}").into());
+            }
+        }
+
+        Ok(Self { args, script })
+    }
+}
+
+/// Resolves a relative path given in a configuration file against the
+/// directory of the file, so that it does not depend on the working
+/// directory of the server.
+fn relative_to(config: &Path, value: toml::Value) -> toml::Value {
+    match (config.parent(), value) {
+        (Some(dir), toml::Value::String(path)) => {
+            toml::Value::String(dir.join(path).to_string_lossy().into_owned())
+        }
+        (_, value) => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::cli::Cli;
+
+    /// A configuration file in a directory of its own.
+    struct TempConfig(PathBuf);
+
+    impl TempConfig {
+        fn new(name: &str, toml: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("tinysse-config-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("tinysse.toml"), toml).unwrap();
+            Self(dir)
+        }
+
+        fn load(&self, args: &[&str]) -> Result<Cli, clap::Error> {
+            let path = self.0.join("tinysse.toml");
+            let config = ["tinysse", "--config", path.to_str().unwrap()];
+            Cli::try_load_from(config.iter().chain(args))
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_command_line_takes_precedence() {
+        let config = TempConfig::new(
+            "precedence",
+            r#"
+                keep_alive = "30s"
+                capacity = 10
+                listen = ["127.0.0.1:1983", "[::1]:1983"]
+            "#,
+        );
+        let cli = config.load(&["--keep-alive", "5s"]).unwrap();
+
+        assert_eq!(cli.keep_alive, std::time::Duration::from_secs(5));
+        assert_eq!(cli.capacity, 10);
+        assert_eq!(cli.listen.len(), 2);
+    }
+
+    #[test]
+    fn test_requirements_across_file_and_command_line() {
+        let config = TempConfig::new("requires", "script_sandbox = true");
+        let cli = config.load(&["--script-allow-path", "/tmp"]).unwrap();
+        assert!(cli.script_sandbox);
+        assert_eq!(cli.script_allow_path, [PathBuf::from("/tmp")]);
+
+        let config = TempConfig::new("requires-tls", r#"tls_cert = "/etc/cert.pem""#);
+        let cli = config.load(&["--tls-key", "/etc/key.pem"]).unwrap();
+        assert_eq!(cli.tls_cert, Some(PathBuf::from("/etc/cert.pem")));
+        assert_eq!(cli.tls_key, Some(PathBuf::from("/etc/key.pem")));
+
+        let config = TempConfig::new("requires-missing", "");
+        assert!(config.load(&["--tls-key", "/etc/key.pem"]).is_err());
+    }
+
+    #[test]
+    fn test_paths_are_relative_to_config() {
+        let config = TempConfig::new(
+            "paths",
+            r#"
+                tls_cert = "cert.pem"
+                tls_key = "/etc/key.pem"
+                history_size = 10
+                history_db = "history.db"
+                serve_static_dir = "static"
+                script_sandbox = true
+                script_allow_path = ["data", "/tmp"]
+
+                [script]
+                path = "script.lua"
+            "#,
+        );
+        let cli = config.load(&[]).unwrap();
+
+        assert_eq!(cli.script, Some(config.0.join("script.lua")));
+        assert_eq!(cli.tls_cert, Some(config.0.join("cert.pem")));
+        assert_eq!(cli.tls_key, Some(PathBuf::from("/etc/key.pem")));
+        assert_eq!(cli.history_db, Some(config.0.join("history.db")));
+        assert_eq!(cli.serve_static_dir, Some(config.0.join("static")));
+        assert_eq!(
+            cli.script_allow_path,
+            [config.0.join("data"), PathBuf::from("/tmp")]
+        );
+
+        // Paths given on the command line stay relative to the working
+        // directory
+        let cli = config.load(&["--history-db", "other.db"]).unwrap();
+        assert_eq!(cli.history_db, Some(PathBuf::from("other.db")));
+    }
+
+    #[test]
+    fn test_unknown_option() {
+        let config = TempConfig::new("unknown", "no_such_option = 1");
+        assert!(config.load(&[]).is_err());
+    }
+}
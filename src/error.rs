@@ -10,6 +10,7 @@ pub enum AppError {
     UnsupportedMediaType(String),
     PayloadTooLarge(String),
     Forbidden(String),
+    Unauthorized(String),
 }
 
 impl AppError {
@@ -42,6 +43,15 @@ impl IntoResponse for AppError {
             Self::PayloadTooLarge(s) => Self::into_json_response(StatusCode::PAYLOAD_TOO_LARGE, s),
 
             Self::Forbidden(s) => Self::into_json_response(StatusCode::FORBIDDEN, s),
+
+            Self::Unauthorized(s) => {
+                let mut res = Self::into_json_response(StatusCode::UNAUTHORIZED, s);
+                res.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    header::HeaderValue::from_static("Bearer"),
+                );
+                res
+            }
         }
     }
 }
@@ -0,0 +1,816 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use tokio_sqlite as sqlite;
+
+use crate::req::{PubReq, SubReq};
+
+/// The number of messages inserted in the SQLite history between evictions,
+/// so that a publish usually runs a single statement.
+const EVICT_INTERVAL: usize = 64;
+
+/// A log of published messages used to catch-up reconnecting subscribers
+/// from their `Last-Event-ID`.
+///
+/// The log is kept either in an in-memory ring buffer or in a SQLite
+/// database that survives restarts.  It is bounded both by the number of
+/// messages it holds and, optionally, by their age.
+#[derive(Debug, Clone)]
+pub struct History {
+    size: usize,
+    ttl: Duration,
+    /// Tells the positions of this history from those of another, such as
+    /// an in-memory history before a restart.
+    epoch: u32,
+    store: Store,
+}
+
+#[derive(Debug, Clone)]
+enum Store {
+    Disabled,
+    Memory(Arc<Mutex<MemoryLog>>),
+    Sqlite(Arc<tokio::sync::Mutex<SqliteLog>>),
+}
+
+/// The in-memory message log, with the sequence number of the last message.
+#[derive(Debug, Default)]
+struct MemoryLog {
+    entries: VecDeque<Entry>,
+    seq: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    at: Instant,
+    pub_req: PubReq,
+}
+
+/// The position of a message in the history.
+///
+/// Long-polling subscribers are given the position of the last message they
+/// received as a cursor, to catch-up from in their next poll even when the
+/// messages have no id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    epoch: u32,
+    seq: u64,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once('-')
+            .and_then(|(epoch, seq)| {
+                Some(Self {
+                    epoch: epoch.parse().ok()?,
+                    seq: seq.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| format!("invalid history cursor {s:?}"))
+    }
+}
+
+impl History {
+    /// Creates an in-memory history.  A size of zero disables the history.
+    pub fn memory(size: usize, ttl: Duration) -> Self {
+        let store = if size > 0 {
+            Store::Memory(Arc::new(Mutex::new(MemoryLog::default())))
+        } else {
+            Store::Disabled
+        };
+
+        Self {
+            size,
+            ttl,
+            epoch: rand::random(),
+            store,
+        }
+    }
+
+    /// Opens (or creates) a history stored in a SQLite database.  A size of
+    /// zero disables the history, as in memory.
+    pub async fn sqlite<P: AsRef<Path>>(
+        path: P,
+        size: usize,
+        ttl: Duration,
+    ) -> anyhow::Result<Self> {
+        if size == 0 {
+            tracing::warn!("history database is not used because --history-size is 0");
+            return Ok(Self::memory(size, ttl));
+        }
+
+        let log = SqliteLog::open(path.as_ref(), size, ttl)
+            .await
+            .with_context(|| format!("open history database {}", path.as_ref().display()))?;
+
+        // The sequence numbers of the database outlive the server, and so
+        // do its positions
+        Ok(Self {
+            size,
+            ttl,
+            epoch: 0,
+            store: Store::Sqlite(Arc::new(tokio::sync::Mutex::new(log))),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self.store, Store::Disabled)
+    }
+
+    /// Records a published message, evicting the oldest messages that
+    /// exceed the size or age bounds.
+    ///
+    /// Returns the position of the message, if it was recorded.
+    pub async fn push(&self, pub_req: &PubReq) -> anyhow::Result<Option<Position>> {
+        if pub_req.msg().is_empty() {
+            return Ok(None);
+        }
+
+        let seq = match &self.store {
+            Store::Disabled => return Ok(None),
+            Store::Memory(log) => {
+                let now = Instant::now();
+                let mut log = log.lock().expect("lock history");
+
+                log.seq += 1;
+                let seq = log.seq;
+                let pub_req = pub_req.clone().with_position(Some(self.position(seq)));
+                log.entries.push_back(Entry { at: now, pub_req });
+
+                while log.entries.len() > self.size {
+                    log.entries.pop_front();
+                }
+
+                self.evict_expired(&mut log.entries, now);
+                seq
+            }
+            Store::Sqlite(log) => {
+                let mut log = log.lock().await;
+
+                log.insert(pub_req).await?;
+
+                if log.inserts % EVICT_INTERVAL == 0 {
+                    log.evict(self.size, self.ttl).await?;
+                }
+
+                log.seq
+            }
+        };
+
+        Ok(Some(self.position(seq)))
+    }
+
+    /// The position of the last message recorded, or `None` if the history
+    /// is disabled.
+    pub async fn last_position(&self) -> Option<Position> {
+        let seq = match &self.store {
+            Store::Disabled => return None,
+            Store::Memory(log) => log.lock().expect("lock history").seq,
+            Store::Sqlite(log) => log.lock().await.seq,
+        };
+
+        Some(self.position(seq))
+    }
+
+    fn position(&self, seq: u64) -> Position {
+        Position {
+            epoch: self.epoch,
+            seq,
+        }
+    }
+
+    /// Returns the messages for a subscriber that were published after the
+    /// message with the given id.
+    ///
+    /// If the id is no longer (or never was) in the history, then no message
+    /// is returned, rather than replaying the whole history to a subscriber
+    /// that may have received it already.  Returns `None` if the history is
+    /// disabled or the subscriber did not give a last event id.
+    pub async fn after(
+        &self,
+        sub_req: &SubReq,
+        last_event_id: Option<&str>,
+    ) -> anyhow::Result<Option<Vec<PubReq>>> {
+        let Some(last_event_id) = last_event_id else {
+            return Ok(None);
+        };
+
+        let pub_reqs =
+            match &self.store {
+                Store::Disabled => return Ok(None),
+                Store::Memory(log) => {
+                    let mut log = log.lock().expect("lock history");
+                    self.evict_expired(&mut log.entries, Instant::now());
+
+                    let Some(pos) = log.entries.iter().rposition(|entry| {
+                        entry.pub_req.msg().id.as_deref() == Some(last_event_id)
+                    }) else {
+                        return Ok(Some(Vec::new()));
+                    };
+
+                    log.entries
+                        .range(pos + 1..)
+                        .map(|entry| entry.pub_req.clone())
+                        .collect::<Vec<_>>()
+                }
+                Store::Sqlite(log) => {
+                    let mut log = log.lock().await;
+
+                    match log.seq_of(last_event_id).await? {
+                        Some(seq) => log.after(seq, self.size, self.ttl).await?,
+                        None => Vec::new(),
+                    }
+                }
+            };
+
+        Ok(Some(self.routed_to(sub_req, pub_reqs)))
+    }
+
+    /// Returns the messages for a subscriber that were recorded after a
+    /// position, and at most the size of the history.
+    ///
+    /// A position from another history, or past the last message, is
+    /// unknown and no message is returned.  Returns `None` if the history is
+    /// disabled.
+    pub async fn after_position(
+        &self,
+        sub_req: &SubReq,
+        position: Position,
+    ) -> anyhow::Result<Option<Vec<PubReq>>> {
+        let Some(last) = self.last_position().await else {
+            return Ok(None);
+        };
+
+        if position.epoch != self.epoch || position > last {
+            return Ok(Some(Vec::new()));
+        }
+
+        let pub_reqs = match &self.store {
+            Store::Disabled => return Ok(None),
+            Store::Memory(log) => {
+                let mut log = log.lock().expect("lock history");
+                self.evict_expired(&mut log.entries, Instant::now());
+
+                log.entries
+                    .iter()
+                    .filter(|entry| entry.pub_req.position() > Some(position))
+                    .map(|entry| entry.pub_req.clone())
+                    .collect()
+            }
+            Store::Sqlite(log) => {
+                log.lock()
+                    .await
+                    .after(position.seq, self.size, self.ttl)
+                    .await?
+            }
+        };
+
+        Ok(Some(self.routed_to(sub_req, pub_reqs)))
+    }
+
+    fn routed_to(&self, sub_req: &SubReq, pub_reqs: Vec<PubReq>) -> Vec<PubReq> {
+        pub_reqs
+            .into_iter()
+            .filter(|pub_req| is_routed_to(pub_req, sub_req))
+            .collect()
+    }
+
+    fn evict_expired(&self, entries: &mut VecDeque<Entry>, now: Instant) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        while entries
+            .front()
+            .is_some_and(|entry| now.duration_since(entry.at) > self.ttl)
+        {
+            entries.pop_front();
+        }
+    }
+}
+
+/// Whether a message is routed to a subscriber by their topics.
+fn is_routed_to(pub_req: &PubReq, sub_req: &SubReq) -> bool {
+    if sub_req.topics().is_empty() {
+        pub_req.topics().is_empty()
+    } else {
+        pub_req
+            .topics()
+            .iter()
+            .any(|topic| sub_req.topics().contains(topic))
+    }
+}
+
+/// The SQLite message log.
+///
+/// Messages are stored with their topics and the address, method and path
+/// of the request they were published with, but not its query and headers.
+/// Other fields set on the `pub` table by the `publish(pub)` script function
+/// are not stored.
+///
+/// The oldest messages are only evicted every `EVICT_INTERVAL` inserts, so
+/// the table can briefly hold more than the size of the history, but no more
+/// than that size is ever replayed.
+struct SqliteLog {
+    conn: sqlite::Connection,
+    inserts: usize,
+    /// The sequence number of the last message inserted
+    seq: u64,
+}
+
+impl std::fmt::Debug for SqliteLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteLog").finish_non_exhaustive()
+    }
+}
+
+impl SqliteLog {
+    async fn open(path: &Path, size: usize, ttl: Duration) -> Result<Self, sqlite::Error> {
+        let mut conn = sqlite::Connection::open(path).await?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tinysse_history (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                at INTEGER NOT NULL,
+                msg_id TEXT,
+                topics TEXT NOT NULL,
+                req TEXT NOT NULL,
+                msg TEXT NOT NULL
+            )",
+            [],
+        )
+        .await?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS tinysse_history_msg_id ON tinysse_history (msg_id)",
+            [],
+        )
+        .await?;
+
+        // The last sequence number is kept by SQLite even when the table is
+        // empty, so that it never goes back
+        let seq = match conn
+            .query_row(
+                "SELECT seq FROM sqlite_sequence WHERE name = 'tinysse_history'",
+                [],
+            )
+            .await?
+            .as_ref()
+            .and_then(|row| row.values().first())
+        {
+            Some(sqlite::Value::Integer(seq)) => *seq as u64,
+            _ => 0,
+        };
+
+        let mut log = Self {
+            conn,
+            inserts: 0,
+            seq,
+        };
+
+        // Trim a database left by a run with a larger history
+        log.evict(size, ttl).await?;
+
+        Ok(log)
+    }
+
+    async fn insert(&mut self, pub_req: &PubReq) -> anyhow::Result<()> {
+        let status = self
+            .conn
+            .execute(
+                "INSERT INTO tinysse_history (at, msg_id, topics, req, msg) VALUES (?, ?, ?, ?, ?)",
+                [
+                    sqlite::Value::Integer(now_millis()),
+                    pub_req
+                        .msg()
+                        .id
+                        .clone()
+                        .map(sqlite::Value::Text)
+                        .unwrap_or(sqlite::Value::Null),
+                    sqlite::Value::Text(serde_json::to_string(pub_req.topics())?),
+                    sqlite::Value::Text(serde_json::to_string(
+                        &pub_req.req().without_credentials(),
+                    )?),
+                    sqlite::Value::Text(serde_json::to_string(pub_req.msg())?),
+                ],
+            )
+            .await?;
+        self.inserts += 1;
+        self.seq = status.last_insert_id().unwrap_or_default() as u64;
+
+        Ok(())
+    }
+
+    async fn evict(&mut self, size: usize, ttl: Duration) -> Result<(), sqlite::Error> {
+        self.conn
+            .execute(
+                "DELETE FROM tinysse_history
+                 WHERE seq <= (SELECT MAX(seq) FROM tinysse_history) - ?",
+                [sqlite::Value::Integer(size as i64)],
+            )
+            .await?;
+
+        if !ttl.is_zero() {
+            self.conn
+                .execute(
+                    "DELETE FROM tinysse_history WHERE at < ?",
+                    [sqlite::Value::Integer(expired_before(ttl))],
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// The sequence number of the last message with the given id.
+    async fn seq_of(&mut self, last_event_id: &str) -> Result<Option<u64>, sqlite::Error> {
+        let seq = match self
+            .conn
+            .query_row(
+                "SELECT MAX(seq) FROM tinysse_history WHERE msg_id = ?",
+                [sqlite::Value::Text(last_event_id.to_string())],
+            )
+            .await?
+            .as_ref()
+            .and_then(|row| row.values().first())
+        {
+            Some(sqlite::Value::Integer(seq)) => Some(*seq as u64),
+            _ => None,
+        };
+
+        Ok(seq)
+    }
+
+    /// The messages after the given sequence number, out of the newest
+    /// `size` messages.
+    async fn after(
+        &mut self,
+        start: u64,
+        size: usize,
+        ttl: Duration,
+    ) -> anyhow::Result<Vec<PubReq>> {
+        let expired_before = if ttl.is_zero() {
+            i64::MIN
+        } else {
+            expired_before(ttl)
+        };
+
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT seq, req, msg, topics FROM tinysse_history
+                 WHERE seq > ? AND at >= ?
+                   AND seq > (SELECT MAX(seq) FROM tinysse_history) - ?
+                 ORDER BY seq",
+                [
+                    sqlite::Value::Integer(start as i64),
+                    sqlite::Value::Integer(expired_before),
+                    sqlite::Value::Integer(size as i64),
+                ],
+            )
+            .await?;
+
+        let mut pub_reqs = Vec::new();
+
+        while let Some(row) = rows.next().await {
+            match row?.values() {
+                [
+                    sqlite::Value::Integer(seq),
+                    sqlite::Value::Text(req),
+                    sqlite::Value::Text(msg),
+                    sqlite::Value::Text(topics),
+                ] => pub_reqs.push(
+                    PubReq::new(
+                        serde_json::from_str(req)?,
+                        serde_json::from_str(msg)?,
+                        serde_json::from_str(topics)?,
+                    )
+                    .with_position(Some(Position {
+                        epoch: 0,
+                        seq: *seq as u64,
+                    })),
+                ),
+                values => anyhow::bail!("unexpected history row {values:?}"),
+            }
+        }
+
+        Ok(pub_reqs)
+    }
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn expired_before(ttl: Duration) -> i64 {
+    now_millis().saturating_sub(ttl.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{msg::Msg, req::Req};
+
+    fn pub_req(id: &str, topics: &[&str]) -> PubReq {
+        let msg = Msg {
+            id: Some(id.to_string()),
+            data: Some(id.to_string()),
+            ..Default::default()
+        };
+
+        PubReq::new(
+            Req::script("/pub"),
+            msg,
+            topics.iter().map(|topic| topic.to_string()).collect(),
+        )
+    }
+
+    fn sub_req(topics: &[&str]) -> SubReq {
+        SubReq::new(
+            Req::script("/sub"),
+            topics.iter().map(|topic| topic.to_string()).collect(),
+        )
+    }
+
+    fn ids(pub_reqs: Option<Vec<PubReq>>) -> Vec<String> {
+        pub_reqs
+            .expect("history is enabled")
+            .iter()
+            .filter_map(|pub_req| pub_req.msg().id.clone())
+            .collect()
+    }
+
+    async fn push_all(history: &History, ids: &[&str]) -> Vec<Option<Position>> {
+        let mut positions = Vec::new();
+        for id in ids {
+            positions.push(history.push(&pub_req(id, &[])).await.unwrap());
+        }
+        positions
+    }
+
+    /// A database path of its own for each test, removed when dropped.
+    struct TempDb(std::path::PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("tinysse-history-{}-{name}.db", std::process::id()));
+            let db = Self(path);
+            db.remove();
+            db
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-shm", "-journal"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    #[test]
+    fn position_round_trip() {
+        let position = Position { epoch: 7, seq: 42 };
+
+        assert_eq!(position.to_string(), "7-42");
+        assert_eq!("7-42".parse::<Position>(), Ok(position));
+
+        for cursor in ["", "7", "7-", "-42", "a-42", "7-b", "7-42-1", "-1-2"] {
+            assert!(cursor.parse::<Position>().is_err(), "{cursor:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_history() {
+        let history = History::memory(0, Duration::ZERO);
+
+        assert!(!history.is_enabled());
+        assert_eq!(history.push(&pub_req("1", &[])).await.unwrap(), None);
+        assert!(
+            history
+                .after(&sub_req(&[]), Some("1"))
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(history.last_position().await, None);
+    }
+
+    #[tokio::test]
+    async fn memory_after_known_and_unknown_ids() {
+        let history = History::memory(10, Duration::ZERO);
+        push_all(&history, &["1", "2", "3"]).await;
+        let sub = sub_req(&[]);
+
+        assert_eq!(
+            ids(history.after(&sub, Some("1")).await.unwrap()),
+            ["2", "3"]
+        );
+        assert_eq!(
+            ids(history.after(&sub, Some("3")).await.unwrap()),
+            Vec::<String>::new()
+        );
+        // An unknown id replays nothing rather than the whole history
+        assert_eq!(
+            ids(history.after(&sub, Some("0")).await.unwrap()),
+            Vec::<String>::new()
+        );
+        assert!(history.after(&sub, None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn memory_after_the_last_message_with_an_id() {
+        let history = History::memory(10, Duration::ZERO);
+        push_all(&history, &["1", "x", "2", "x", "3"]).await;
+
+        assert_eq!(
+            ids(history.after(&sub_req(&[]), Some("x")).await.unwrap()),
+            ["3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_evicts_by_size() {
+        let history = History::memory(3, Duration::ZERO);
+        push_all(&history, &["1", "2", "3", "4", "5"]).await;
+        let sub = sub_req(&[]);
+
+        assert_eq!(
+            ids(history.after(&sub, Some("2")).await.unwrap()),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            ids(history.after(&sub, Some("3")).await.unwrap()),
+            ["4", "5"]
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_evicts_by_ttl() {
+        let history = History::memory(10, Duration::from_millis(50));
+        push_all(&history, &["1", "2"]).await;
+        std::thread::sleep(Duration::from_millis(100));
+        push_all(&history, &["3", "4"]).await;
+        let sub = sub_req(&[]);
+
+        assert_eq!(
+            ids(history.after(&sub, Some("1")).await.unwrap()),
+            Vec::<String>::new()
+        );
+        assert_eq!(ids(history.after(&sub, Some("3")).await.unwrap()), ["4"]);
+    }
+
+    #[tokio::test]
+    async fn after_routes_by_topics() {
+        let history = History::memory(10, Duration::ZERO);
+        history.push(&pub_req("0", &[])).await.unwrap();
+        history.push(&pub_req("1", &["a"])).await.unwrap();
+        history.push(&pub_req("2", &["b"])).await.unwrap();
+        history.push(&pub_req("3", &["a", "b"])).await.unwrap();
+        history.push(&pub_req("4", &[])).await.unwrap();
+
+        assert_eq!(
+            ids(history.after(&sub_req(&["a"]), Some("0")).await.unwrap()),
+            ["1", "3"]
+        );
+        assert_eq!(
+            ids(history.after(&sub_req(&["b"]), Some("0")).await.unwrap()),
+            ["2", "3"]
+        );
+        assert_eq!(
+            ids(history.after(&sub_req(&[]), Some("0")).await.unwrap()),
+            ["4"]
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_after_position() {
+        let history = History::memory(10, Duration::ZERO);
+        let positions = push_all(&history, &["1", "2", "3"]).await;
+        let first = positions[0].unwrap();
+        let last = positions[2].unwrap();
+        let sub = sub_req(&[]);
+
+        assert!(positions.windows(2).all(|p| p[0] < p[1]));
+        assert_eq!(history.last_position().await, Some(last));
+        assert_eq!(
+            ids(history.after_position(&sub, first).await.unwrap()),
+            ["2", "3"]
+        );
+        assert_eq!(
+            ids(history.after_position(&sub, last).await.unwrap()),
+            Vec::<String>::new()
+        );
+
+        // A position of another history, or past the last message, is unknown
+        let other = Position {
+            epoch: first.epoch.wrapping_add(1),
+            seq: 0,
+        };
+        let future = Position {
+            seq: last.seq + 1,
+            ..last
+        };
+        assert_eq!(
+            ids(history.after_position(&sub, other).await.unwrap()),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            ids(history.after_position(&sub, future).await.unwrap()),
+            Vec::<String>::new()
+        );
+    }
+
+    // The SQLite connection blocks in place when it is dropped, which needs
+    // the multi-threaded runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sqlite_caps_replay_and_keeps_order() {
+        let db = TempDb::new("cap");
+        let history = History::sqlite(&db.0, 3, Duration::ZERO).await.unwrap();
+        push_all(&history, &["1", "2", "3", "4", "5", "6"]).await;
+        let sub = sub_req(&[]);
+
+        // The table is only trimmed in batches, but no more than the size
+        // of the history is replayed
+        assert_eq!(
+            ids(history.after(&sub, Some("1")).await.unwrap()),
+            ["4", "5", "6"]
+        );
+        assert_eq!(
+            ids(history.after(&sub, Some("4")).await.unwrap()),
+            ["5", "6"]
+        );
+        assert_eq!(
+            ids(history.after(&sub, Some("9")).await.unwrap()),
+            Vec::<String>::new()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sqlite_evicts_by_size_on_open_and_keeps_positions() {
+        let db = TempDb::new("reopen");
+        let positions = {
+            let history = History::sqlite(&db.0, 10, Duration::ZERO).await.unwrap();
+            push_all(&history, &["1", "2", "3", "4", "5"]).await
+        };
+
+        let history = History::sqlite(&db.0, 2, Duration::ZERO).await.unwrap();
+        let sub = sub_req(&[]);
+
+        assert_eq!(history.last_position().await, positions[4]);
+        assert_eq!(
+            ids(history.after(&sub, Some("3")).await.unwrap()),
+            Vec::<String>::new()
+        );
+        assert_eq!(ids(history.after(&sub, Some("4")).await.unwrap()), ["5"]);
+        assert_eq!(
+            ids(history
+                .after_position(&sub, positions[3].unwrap())
+                .await
+                .unwrap()),
+            ["5"]
+        );
+
+        // New messages continue the sequence of the database
+        let next = history.push(&pub_req("6", &[])).await.unwrap().unwrap();
+        assert!(Some(next) > positions[4]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sqlite_evicts_by_ttl() {
+        let db = TempDb::new("ttl");
+        let history = History::sqlite(&db.0, 10, Duration::from_millis(50))
+            .await
+            .unwrap();
+        push_all(&history, &["1", "2"]).await;
+        std::thread::sleep(Duration::from_millis(100));
+        push_all(&history, &["3", "4"]).await;
+        let sub = sub_req(&[]);
+
+        assert_eq!(
+            ids(history.after(&sub, Some("1")).await.unwrap()),
+            ["3", "4"]
+        );
+        assert_eq!(ids(history.after(&sub, Some("3")).await.unwrap()), ["4"]);
+    }
+}
@@ -1,9 +1,15 @@
+pub mod admin;
 pub mod cli;
+pub mod config;
 pub mod error;
+pub mod history;
+pub mod metrics;
 pub mod msg;
 pub mod req;
+pub mod sandbox;
 pub mod script;
+pub mod server;
 pub mod state;
-pub mod types;
+pub mod tls;
 pub mod userdata;
 pub mod web;
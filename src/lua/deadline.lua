@@ -0,0 +1,33 @@
+-- Arms the deadline of the script function that the worker is running on
+-- the coroutines that it resumes.  A Lua state has a single thread hook, so
+-- the deadline is armed on a coroutine before it is resumed, and again on
+-- the caller once it yields or returns.
+local arm = ...
+
+local create, resume, running = coroutine.create, coroutine.resume, coroutine.running
+
+-- Passes the results of a coroutine through once the caller is armed again
+local function rearm(...)
+    arm((running()))
+    return ...
+end
+
+-- Raises the error of a coroutine at the caller, as `coroutine.wrap` does
+local function unwrap(ok, ...)
+    if not ok then
+        error((...), 0)
+    end
+    return ...
+end
+
+function coroutine.resume(co, ...)
+    arm(co)
+    return rearm(resume(co, ...))
+end
+
+function coroutine.wrap(f)
+    local co = create(f)
+    return function(...)
+        return unwrap(coroutine.resume(co, ...))
+    end
+end
@@ -25,3 +25,12 @@ end
 
 function timeout(sub, elapsed)
 end
+
+function lagged(sub, missed)
+end
+
+function shutdown()
+end
+
+function reload(old)
+end
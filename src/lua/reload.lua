@@ -0,0 +1,27 @@
+-- Replaces the globals with those defined by a reloaded script, and the
+-- functions it no longer defines with their defaults.  Returns the replaced
+-- values for the `reload(old)` function.
+local env, defaults = ...
+local old = {}
+
+for name, value in pairs(env) do
+    old[name] = _G[name]
+    _G[name] = value
+end
+
+for name, func in pairs(defaults) do
+    if rawget(env, name) == nil then
+        old[name] = _G[name]
+        _G[name] = func
+    end
+end
+
+-- The functions of the script keep their environment, which from now on
+-- reads and writes the globals
+for name in pairs(env) do
+    env[name] = nil
+end
+
+setmetatable(env, { __index = _G, __newindex = _G })
+
+return old
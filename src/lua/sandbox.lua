@@ -0,0 +1,131 @@
+-- Restricts the standard library functions that access files, environment
+-- variables and commands to the capabilities allowed by the sandbox.  The
+-- original functions are only kept as locals of this chunk, out of reach of
+-- the script.
+local allowed = ...
+
+-- Raises the error of an access that is not allowed at the caller of the
+-- restricted function
+local allow = {}
+
+for name, check in pairs(allowed) do
+    allow[name] = function(...)
+        local err = check(...)
+        if err ~= nil then
+            error(err, 3)
+        end
+    end
+end
+
+local io_open, io_lines, io_input, io_output, io_popen =
+    io.open, io.lines, io.input, io.output, io.popen
+local os_execute, os_getenv, os_remove, os_rename, os_tmpname =
+    os.execute, os.getenv, os.remove, os.rename, os.tmpname
+local lua_load, lua_loadfile = load, loadfile
+local searchpath = package.searchpath
+
+function io.open(path, ...)
+    allow.path(path)
+    return io_open(path, ...)
+end
+
+function io.lines(path, ...)
+    if path ~= nil then
+        allow.path(path)
+    end
+    return io_lines(path, ...)
+end
+
+function io.input(file)
+    if type(file) == "string" then
+        allow.path(file)
+    end
+    return io_input(file)
+end
+
+function io.output(file)
+    if type(file) == "string" then
+        allow.path(file)
+    end
+    return io_output(file)
+end
+
+function io.popen(command, ...)
+    allow.exec(command)
+    return io_popen(command, ...)
+end
+
+function os.execute(command)
+    if command ~= nil then
+        allow.exec(command)
+    end
+    return os_execute(command)
+end
+
+function os.getenv(name)
+    allow.env(name)
+    return os_getenv(name)
+end
+
+function os.remove(path)
+    allow.path(path)
+    return os_remove(path)
+end
+
+function os.rename(from, to)
+    allow.path(from)
+    allow.path(to)
+    return os_rename(from, to)
+end
+
+function os.tmpname()
+    -- The file is created before its name is known
+    local path = os_tmpname()
+    local err = allowed.path(path)
+    if err ~= nil then
+        os_remove(path)
+        error(err, 2)
+    end
+    return path
+end
+
+function os.exit()
+    error("os.exit is not allowed by the script sandbox", 2)
+end
+
+-- Precompiled chunks can break the Lua state, so only load source code
+function load(chunk, name, mode, env)
+    return lua_load(chunk, name, "t", env)
+end
+
+function loadfile(path, mode, env)
+    if path ~= nil then
+        allow.path(path)
+    end
+    return lua_loadfile(path, "t", env)
+end
+
+function dofile(path)
+    if path ~= nil then
+        allow.path(path)
+    end
+    local chunk, err = lua_loadfile(path, "t")
+    if chunk == nil then
+        error(err, 2)
+    end
+    return chunk()
+end
+
+-- Look for the Lua modules of `require` in the allowed paths only
+package.searchers[2] = function(name)
+    local path, err = searchpath(name, package.path)
+    if path == nil then
+        return err
+    end
+    allow.path(path)
+    local chunk, err = lua_loadfile(path, "t")
+    if chunk == nil then
+        error(string.format("error loading module '%s' from file '%s':\n\t%s", name, path, err), 2)
+    end
+    return chunk, path
+end
@@ -1,22 +1,35 @@
-use std::net::SocketAddr;
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
 
 use axum::Router;
-use clap::Parser;
+use futures::{FutureExt as _, future::FusedFuture as _};
 
-use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_http::{
     LatencyUnit, cors,
+    sensitive_headers::SetSensitiveRequestHeadersLayer,
     trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
 };
 
 use tracing_subscriber::EnvFilter;
 
-use tinysse::{cli::Cli, state::AppState, web};
+use tinysse::{
+    cli::{Cli, Listen},
+    server::{self, Listener},
+    state::AppState,
+    tls::Tls,
+    web,
+};
+
+/// How often the Lua script is checked for changes with `--script-watch`.
+const SCRIPT_WATCH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() {
-    let cli = Cli::parse();
+    let cli = Cli::load();
 
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::new(cli.log_level.as_str()))
@@ -25,7 +38,7 @@ async fn main() {
     tracing::debug!("cli={cli:?}");
 
     if let Err(e) = try_main(&cli).await {
-        tracing::error!("{e}");
+        tracing::error!("{e:#}");
         std::process::exit(1);
     }
 }
@@ -34,43 +47,59 @@ async fn try_main(cli: &Cli) -> anyhow::Result<()> {
     let state = AppState::from_cli(cli).await?;
     tracing::debug!("state={state:?}");
 
-    let router = Router::new()
-        .merge(web::router(&state))
-        .layer(
-            ServiceBuilder::new().layer(
-                TraceLayer::new_for_http()
-                    .make_span_with(DefaultMakeSpan::new().include_headers(true))
-                    .on_request(DefaultOnRequest::new().level(tracing::Level::DEBUG))
-                    .on_response(
-                        DefaultOnResponse::new()
-                            .level(tracing::Level::DEBUG)
-                            .latency_unit(LatencyUnit::Millis),
-                    ),
-            ),
-        )
-        .layer(
-            ServiceBuilder::new().layer(
-                cors::CorsLayer::new()
-                    .allow_origin(cli.cors_allow_origin.clone())
-                    .allow_methods(cli.cors_allow_methods.clone())
-                    .allow_headers(cli.cors_allow_headers.clone())
-                    .allow_credentials(cli.cors_allow_credentials)
-                    .max_age(cli.cors_max_age),
-            ),
+    // The publish route moves to the internal listeners if there are any
+    let (router, internal_router) = if cli.internal_listen.is_empty() {
+        (app(web::router(&state), &state, cli), None)
+    } else {
+        (
+            app(web::public_router(&state), &state, cli),
+            Some(app(web::internal_router(&state), &state, cli)),
         )
-        .with_state(state.clone())
-        .into_make_service_with_connect_info::<SocketAddr>();
+    };
+
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(Tls::load(cert, key)?),
+        _ => None,
+    };
+
+    let mut listeners = Vec::with_capacity(cli.listen.len() + cli.internal_listen.len());
+
+    for listen in &cli.listen {
+        let listener = Listener::bind(listen, cli.unix_socket_mode).await?;
+        listeners.push((listener, router.clone(), tls.clone(), ""));
+    }
+
+    // The internal listeners are served over plain HTTP
+    if let Some(internal_router) = internal_router {
+        for listen in &cli.internal_listen {
+            let listener = Listener::bind(listen, cli.unix_socket_mode).await?;
+            listeners.push((listener, internal_router.clone(), None, " internal"));
+        }
+    }
 
-    let listener = TcpListener::bind(&cli.listen).await?;
-    let local_addr = listener.local_addr()?;
     tracing::info!(
         "Started {}",
         concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))
     );
-    tracing::info!("Listening on {local_addr}");
+
+    for (listener, _, tls, kind) in &listeners {
+        let scheme = if tls.is_some() { "https" } else { "http" };
+
+        match listener.local_addr()? {
+            Listen::Tcp(addr) => tracing::info!("Listening on {scheme}://{addr}{kind}"),
+            listen @ Listen::Unix(_) => tracing::info!("Listening on {listen} ({scheme}){kind}"),
+        }
+    }
 
     state.script.startup(cli).await?;
 
+    let servers =
+        futures::future::try_join_all(listeners.into_iter().map(|(listener, router, tls, _)| {
+            server::serve(listener, router, tls, state.shutdown.clone())
+        }))
+        .fuse();
+    tokio::pin!(servers);
+
     tokio::select! {
         _ = async {
             // Run the script tick loop
@@ -87,10 +116,154 @@ async fn try_main(cli: &Cli) -> anyhow::Result<()> {
             }
         } => {},
 
-        result = axum::serve(listener, router) => {
+        _ = reload_script(&state, cli) => {},
+
+        result = &mut servers => {
+            // The servers only stop on their own on errors
             result?;
         }
+
+        _ = shutdown_signal() => {}
+    }
+
+    tracing::info!("Shutting down");
+    state.shutdown.cancel();
+
+    // Stop accepting connections and wait for the subscribers to disconnect
+    // and their `unsubscribe` functions to finish.
+    let drained = tokio::time::timeout(cli.shutdown_timeout, async {
+        if !servers.is_terminated()
+            && let Err(e) = (&mut servers).await
+        {
+            tracing::error!("{e}");
+        }
+
+        state.tasks.close();
+        state.tasks.wait().await;
+    })
+    .await;
+
+    if drained.is_err() {
+        tracing::warn!(
+            "Timed out waiting {:?} for subscribers to disconnect",
+            cli.shutdown_timeout
+        );
+    }
+
+    if let Err(e) = state.script.shutdown().await {
+        tracing::error!("{e}");
     }
 
     Ok(())
 }
+
+/// Waits for a Ctrl-C or, on Unix, a SIGTERM signal.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("install Ctrl-C signal handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("install SIGTERM signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Reloads the Lua script on a SIGHUP signal or, with `--script-watch`,
+/// whenever the file is modified.
+///
+/// If the script cannot be reloaded, then the error is logged and the
+/// current script is kept.
+async fn reload_script(state: &AppState, cli: &Cli) {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("install SIGHUP signal handler");
+
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified: Option<SystemTime> = cli.script.as_deref().and_then(modified);
+
+    loop {
+        #[cfg(unix)]
+        let hangup = hangup.recv();
+
+        #[cfg(not(unix))]
+        let hangup = std::future::pending::<()>();
+
+        let watch = async {
+            match &cli.script {
+                Some(path) if cli.script_watch => loop {
+                    tokio::time::sleep(SCRIPT_WATCH_INTERVAL).await;
+
+                    if modified(path) != last_modified {
+                        break;
+                    }
+                },
+                _ => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = hangup => {},
+            _ = watch => {},
+        }
+
+        let Some(path) = &cli.script else {
+            tracing::warn!("No script to reload");
+            continue;
+        };
+
+        // A file modified before a SIGHUP is only reloaded once
+        last_modified = modified(path);
+
+        match state.script.reload(path).await {
+            Ok(()) => tracing::info!("Reloaded script {}", path.display()),
+            Err(e) => tracing::error!("reload script {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Adds the tracing and CORS layers to a router of the application.
+fn app(router: Router<AppState>, state: &AppState, cli: &Cli) -> Router {
+    router
+        .layer(
+            ServiceBuilder::new()
+                // The admin token is not logged with the request headers
+                .layer(SetSensitiveRequestHeadersLayer::new([
+                    http::header::AUTHORIZATION,
+                ]))
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(DefaultMakeSpan::new().include_headers(true))
+                        .on_request(DefaultOnRequest::new().level(tracing::Level::DEBUG))
+                        .on_response(
+                            DefaultOnResponse::new()
+                                .level(tracing::Level::DEBUG)
+                                .latency_unit(LatencyUnit::Millis),
+                        ),
+                ),
+        )
+        .layer(
+            ServiceBuilder::new().layer(
+                cors::CorsLayer::new()
+                    .allow_origin(cli.cors_allow_origin.clone())
+                    .allow_methods(cli.cors_allow_methods.clone())
+                    .allow_headers(cli.cors_allow_headers.clone())
+                    .allow_credentials(cli.cors_allow_credentials)
+                    .max_age(cli.cors_max_age),
+            ),
+        )
+        .with_state(state.clone())
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, atomic::AtomicU64},
+    time::Duration,
+};
+
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text},
+    metrics::{
+        counter::Counter,
+        family::{Family, MetricConstructor},
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+
+/// The server metrics, exported in the Prometheus text format.
+///
+/// Cloning is cheap and every clone updates the same metrics.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+    subscribers: Gauge,
+    subscribers_total: Counter,
+    publishes: Family<ResultLabels, Counter>,
+    messages: Family<ResultLabels, Counter>,
+    queue_depth: Gauge,
+    lagged: Counter,
+    lagged_messages: Counter,
+    catchup_messages: Family<SourceLabels, Counter>,
+    hook_duration: Family<HookLabels, Histogram, fn() -> Histogram>,
+    hook_timeouts: Family<HookLabels, Counter>,
+    script_metrics: Arc<Mutex<HashMap<String, ScriptMetric>>>,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ResultLabels {
+    result: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SourceLabels {
+    source: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct HookLabels {
+    hook: &'static str,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("tinysse");
+
+        let subscribers = Gauge::default();
+        registry.register(
+            "subscribers",
+            "The number of connected subscribers",
+            subscribers.clone(),
+        );
+
+        let subscribers_total = Counter::default();
+        registry.register(
+            "subscriptions",
+            "The number of subscribers that have connected",
+            subscribers_total.clone(),
+        );
+
+        let publishes = Family::default();
+        registry.register(
+            "publishes",
+            "The number of messages published, by whether the `publish` function accepted them",
+            publishes.clone(),
+        );
+
+        let messages = Family::default();
+        registry.register(
+            "messages",
+            "The number of messages for subscribers, by whether the `message` function delivered or filtered them",
+            messages.clone(),
+        );
+
+        let queue_depth = Gauge::default();
+        registry.register(
+            "queue_depth",
+            "The number of messages in the queues of messages without topics and of every topic",
+            queue_depth.clone(),
+        );
+
+        let lagged = Counter::default();
+        registry.register(
+            "lagged",
+            "The number of times subscribers lagged behind the message queue",
+            lagged.clone(),
+        );
+
+        let lagged_messages = Counter::default();
+        registry.register(
+            "lagged_messages",
+            "The number of messages missed by lagging subscribers",
+            lagged_messages.clone(),
+        );
+
+        let catchup_messages = Family::default();
+        registry.register(
+            "catchup_messages",
+            "The number of messages subscribers were caught-up with, by the `catchup` function or the history",
+            catchup_messages.clone(),
+        );
+
+        // From 100µs to about 6.5s
+        let hook_duration: Family<HookLabels, Histogram, fn() -> Histogram> =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.0001, 4.0, 9)));
+        registry.register(
+            "hook_duration_seconds",
+            "The duration of the Lua script function calls",
+            hook_duration.clone(),
+        );
+
+        let hook_timeouts = Family::default();
+        registry.register(
+            "hook_timeouts",
+            "The number of Lua script function calls that were interrupted by their timeout",
+            hook_timeouts.clone(),
+        );
+
+        Self {
+            registry: Arc::new(Mutex::new(registry)),
+            subscribers,
+            subscribers_total,
+            publishes,
+            messages,
+            queue_depth,
+            lagged,
+            lagged_messages,
+            catchup_messages,
+            hook_duration,
+            hook_timeouts,
+            script_metrics: Arc::default(),
+        }
+    }
+
+    /// Records a subscriber connecting.
+    pub fn subscribed(&self) {
+        self.subscribers.inc();
+        self.subscribers_total.inc();
+    }
+
+    /// Records a subscriber disconnecting.
+    pub fn unsubscribed(&self) {
+        self.subscribers.dec();
+    }
+
+    /// Records a published message as `accepted`, `rejected` or `error`.
+    pub fn publish(&self, result: &'static str) {
+        self.publishes.get_or_create(&ResultLabels { result }).inc();
+    }
+
+    /// Records a message for a subscriber as `delivered`, `filtered` or `error`.
+    pub fn message(&self, result: &'static str) {
+        self.messages.get_or_create(&ResultLabels { result }).inc();
+    }
+
+    /// Records a subscriber lagging behind the message queue.
+    pub fn lagged(&self, missed: u64) {
+        self.lagged.inc();
+        self.lagged_messages.inc_by(missed);
+    }
+
+    /// Records the messages a subscriber was caught-up with from a `script`
+    /// or the `history`.
+    pub fn catchup(&self, source: &'static str, count: usize) {
+        self.catchup_messages
+            .get_or_create(&SourceLabels { source })
+            .inc_by(count as u64);
+    }
+
+    /// Records the duration of a Lua script function call.
+    pub fn hook(&self, hook: &'static str, elapsed: Duration) {
+        self.hook_duration
+            .get_or_create(&HookLabels { hook })
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Records a Lua script function call that timed out.
+    pub fn hook_timeout(&self, hook: &'static str) {
+        self.hook_timeouts.get_or_create(&HookLabels { hook }).inc();
+    }
+
+    /// Registers a metric defined by the Lua script.
+    ///
+    /// Script metrics are exported with a `tinysse_script_` prefix so that
+    /// they do not collide with the server metrics.  Registering a metric
+    /// again with the same type and labels returns the existing metric, so
+    /// that scripts can define their metrics wherever they need them.  The
+    /// `_total` suffix of a counter name is optional.
+    pub fn script_metric(
+        &self,
+        name: &str,
+        help: &str,
+        labels: Vec<String>,
+        kind: ScriptMetricKind,
+    ) -> anyhow::Result<ScriptMetric> {
+        // The `_total` suffix of counters is added when they are encoded
+        let name = match kind {
+            ScriptMetricKind::Counter => name.strip_suffix("_total").unwrap_or(name),
+            _ => name,
+        };
+
+        if !is_valid_name(name) {
+            anyhow::bail!("invalid metric name {name:?}");
+        }
+
+        if let Some(label) = labels
+            .iter()
+            .find(|label| !is_valid_name(label) || label.starts_with("__"))
+        {
+            anyhow::bail!("invalid label name {label:?} for metric {name}");
+        }
+
+        let mut script_metrics = self.script_metrics.lock().expect("lock script metrics");
+
+        if let Some(metric) = script_metrics.get(name) {
+            if metric.kind != kind || *metric.labels != *labels {
+                anyhow::bail!("metric {name} is already registered with another type or labels");
+            }
+
+            return Ok(metric.clone());
+        }
+
+        let family = match &kind {
+            ScriptMetricKind::Counter => ScriptFamily::Counter(Family::default()),
+            ScriptMetricKind::Gauge => ScriptFamily::Gauge(Family::default()),
+            ScriptMetricKind::Histogram(buckets) => {
+                if buckets.is_empty()
+                    || buckets.iter().any(|bucket| !bucket.is_finite())
+                    || buckets.windows(2).any(|pair| pair[0] >= pair[1])
+                {
+                    anyhow::bail!("buckets of metric {name} must be increasing numbers");
+                }
+
+                ScriptFamily::Histogram(Family::new_with_constructor(Buckets(
+                    buckets.as_slice().into(),
+                )))
+            }
+        };
+
+        let mut registry = self.registry.lock().expect("lock metrics registry");
+        let name_with_prefix = format!("script_{name}");
+
+        match &family {
+            ScriptFamily::Counter(family) => {
+                registry.register(name_with_prefix, help, family.clone())
+            }
+            ScriptFamily::Gauge(family) => {
+                registry.register(name_with_prefix, help, family.clone())
+            }
+            ScriptFamily::Histogram(family) => {
+                registry.register(name_with_prefix, help, family.clone())
+            }
+        }
+
+        let metric = ScriptMetric {
+            name: name.into(),
+            labels: labels.into(),
+            kind,
+            family,
+        };
+
+        script_metrics.insert(name.to_string(), metric.clone());
+
+        Ok(metric)
+    }
+
+    /// Encodes the metrics in the Prometheus (OpenMetrics) text format, with
+    /// the current depth of the message queues.
+    pub fn encode(&self, queue_depth: usize) -> Result<String, std::fmt::Error> {
+        self.queue_depth.set(queue_depth as i64);
+
+        let mut buf = String::new();
+        text::encode(
+            &mut buf,
+            &self.registry.lock().expect("lock metrics registry"),
+        )?;
+
+        Ok(buf)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The type of a metric defined by the Lua script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptMetricKind {
+    Counter,
+    Gauge,
+    /// A histogram with the upper bounds of its buckets.
+    Histogram(Vec<f64>),
+}
+
+type ScriptLabels = Vec<(String, String)>;
+
+/// A metric defined by the Lua script.
+#[derive(Debug, Clone)]
+pub struct ScriptMetric {
+    name: Arc<str>,
+    labels: Arc<[String]>,
+    kind: ScriptMetricKind,
+    family: ScriptFamily,
+}
+
+#[derive(Debug, Clone)]
+enum ScriptFamily {
+    Counter(Family<ScriptLabels, Counter<f64, AtomicU64>>),
+    Gauge(Family<ScriptLabels, Gauge<f64, AtomicU64>>),
+    Histogram(Family<ScriptLabels, Histogram, Buckets>),
+}
+
+/// Creates the histograms of a script metric with its buckets.
+#[derive(Debug, Clone)]
+struct Buckets(Arc<[f64]>);
+
+impl MetricConstructor<Histogram> for Buckets {
+    fn new_metric(&self) -> Histogram {
+        Histogram::new(self.0.iter().copied())
+    }
+}
+
+impl ScriptMetric {
+    /// Increments a counter or a gauge.
+    pub fn inc(&self, value: f64, labels: &HashMap<String, String>) -> anyhow::Result<()> {
+        let labels = self.label_set(labels)?;
+
+        match &self.family {
+            ScriptFamily::Counter(family) => {
+                if !value.is_finite() || value < 0.0 {
+                    anyhow::bail!("counter {} can only increase", self.name);
+                }
+
+                family.get_or_create(&labels).inc_by(value);
+            }
+            ScriptFamily::Gauge(family) => {
+                family.get_or_create(&labels).inc_by(value);
+            }
+            ScriptFamily::Histogram(_) => {
+                anyhow::bail!("histogram {} cannot be incremented", self.name)
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decrements a gauge.
+    pub fn dec(&self, value: f64, labels: &HashMap<String, String>) -> anyhow::Result<()> {
+        let labels = self.label_set(labels)?;
+
+        match &self.family {
+            ScriptFamily::Gauge(family) => {
+                family.get_or_create(&labels).dec_by(value);
+            }
+            _ => anyhow::bail!("metric {} is not a gauge", self.name),
+        }
+
+        Ok(())
+    }
+
+    /// Sets a gauge.
+    pub fn set(&self, value: f64, labels: &HashMap<String, String>) -> anyhow::Result<()> {
+        let labels = self.label_set(labels)?;
+
+        match &self.family {
+            ScriptFamily::Gauge(family) => {
+                family.get_or_create(&labels).set(value);
+            }
+            _ => anyhow::bail!("metric {} is not a gauge", self.name),
+        }
+
+        Ok(())
+    }
+
+    /// Observes a value in a histogram.
+    pub fn observe(&self, value: f64, labels: &HashMap<String, String>) -> anyhow::Result<()> {
+        let labels = self.label_set(labels)?;
+
+        match &self.family {
+            ScriptFamily::Histogram(family) => family.get_or_create(&labels).observe(value),
+            _ => anyhow::bail!("metric {} is not a histogram", self.name),
+        }
+
+        Ok(())
+    }
+
+    /// The label set of a metric, in the order the labels were defined.
+    /// Every label of the metric must be given, and only those.
+    fn label_set(&self, labels: &HashMap<String, String>) -> anyhow::Result<ScriptLabels> {
+        if let Some(label) = labels.keys().find(|label| !self.labels.contains(label)) {
+            anyhow::bail!("unknown label {label:?} for metric {}", self.name);
+        }
+
+        self.labels
+            .iter()
+            .map(|label| match labels.get(label) {
+                Some(value) => Ok((label.clone(), value.clone())),
+                None => anyhow::bail!("missing label {label:?} for metric {}", self.name),
+            })
+            .collect()
+    }
+}
+
+/// Whether a metric or label name is valid in the Prometheus data model.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
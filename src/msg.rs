@@ -1,13 +1,22 @@
+use std::time::Duration;
+
 use axum::response::sse::Event;
 use mlua::LuaSerdeExt as _;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default, Clone, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Msg {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub event: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub comment: Option<Vec<String>>,
+    /// The reconnection delay in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u64>,
 }
 
 impl Msg {
@@ -16,7 +25,105 @@ impl Msg {
             && self.event.is_none()
             && self.data.is_none()
             && self.comment.as_ref().is_none_or(|c| c.is_empty())
+            && self.retry.is_none()
+    }
+
+    /// The number of bytes of the message in the `text/event-stream` format,
+    /// as it is sent to SSE subscribers.
+    pub fn event_stream_len(&self) -> usize {
+        // Each field is written as `name: value\n`, except for `retry:`
+        let field = |name: &str, value: &str| name.len() + 2 + value.len() + 1;
+
+        self.id.as_deref().map_or(0, |id| field("id", id))
+            + self
+                .event
+                .as_deref()
+                .map_or(0, |event| field("event", event))
+            + self.data.as_deref().map_or(0, |data| {
+                data.split('\n').map(|line| field("data", line)).sum()
+            })
+            + self.comment.as_ref().map_or(0, |comments| {
+                comments.iter().map(|comment| field("", comment)).sum()
+            })
+            + self
+                .retry
+                .map_or(0, |retry| "retry:".len() + retry.to_string().len() + 1)
+            + 1
     }
+
+    /// Parses messages from a `text/event-stream` body.
+    ///
+    /// Follows the event stream interpretation of the HTML specification,
+    /// except that comments are kept as message comments and a final
+    /// message that is not terminated by a blank line is not discarded.
+    /// Blocks without `data` are not messages and are discarded, an empty
+    /// `id` is no id, and invalid `retry` values, `id` values containing NULL,
+    /// and unknown fields are ignored.
+    pub fn parse_event_stream(text: &str) -> Vec<Self> {
+        let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+        let mut msgs = Vec::new();
+        let mut msg = Self::default();
+        let mut data: Option<Vec<&str>> = None;
+
+        for line in split_lines(text) {
+            if line.is_empty() {
+                dispatch(&mut msgs, std::mem::take(&mut msg), data.take());
+                continue;
+            }
+
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line, ""),
+            };
+
+            match field {
+                "" => msg.comment.get_or_insert_default().push(value.to_string()),
+                "event" => msg.event = Some(value.to_string()),
+                "data" => data.get_or_insert_default().push(value),
+                "id" if !value.contains('\0') => {
+                    msg.id = Some(value.to_string()).filter(|id| !id.is_empty());
+                }
+                "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                    msg.retry = value.parse().ok();
+                }
+                _ => {}
+            }
+        }
+
+        dispatch(&mut msgs, msg, data);
+
+        msgs
+    }
+}
+
+/// Adds a parsed message to the messages, unless it has no data.
+fn dispatch(msgs: &mut Vec<Msg>, msg: Msg, data: Option<Vec<&str>>) {
+    if let Some(data) = data {
+        msgs.push(Msg {
+            data: Some(data.join("\n")),
+            ..msg
+        });
+    }
+}
+
+/// Splits text into lines terminated by CRLF, LF or CR.
+fn split_lines(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        let (line, next) = match rest.find(['\r', '\n']) {
+            Some(pos) if rest[pos..].starts_with("\r\n") => (&rest[..pos], &rest[pos + 2..]),
+            Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+            None => (rest, ""),
+        };
+
+        rest = next;
+        Some(line)
+    })
 }
 
 impl mlua::FromLua for Msg {
@@ -41,6 +148,10 @@ impl mlua::FromLua for Msg {
                     msg.comment = comment;
                 }
 
+                msg.retry = tbl.get("retry").map_err(|_| {
+                    mlua::Error::runtime("msg.retry must be a non-negative integer of milliseconds")
+                })?;
+
                 Ok(msg)
             }
             None => Err(mlua::Error::FromLuaConversionError {
@@ -68,10 +179,14 @@ impl mlua::IntoLua for Msg {
             tbl.set("data", data)?;
         }
 
-        if let Some(comments) = self.comment {
-            if !comments.is_empty() {
-                tbl.set("comment", comments)?;
-            }
+        if let Some(comments) = self.comment
+            && !comments.is_empty()
+        {
+            tbl.set("comment", comments)?;
+        }
+
+        if let Some(retry) = self.retry {
+            tbl.set("retry", retry)?;
         }
 
         lua.to_value(&tbl)
@@ -100,6 +215,55 @@ impl From<Msg> for Event {
             }
         }
 
+        if let Some(retry) = msg.retry {
+            event = event.retry(Duration::from_millis(retry));
+        }
+
         event
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multi_line_data() {
+        let msgs = Msg::parse_event_stream("event: update\ndata: first\ndata:second\ndata\n\n");
+
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].event.as_deref(), Some("update"));
+        assert_eq!(msgs[0].data.as_deref(), Some("first\nsecond\n"));
+    }
+
+    #[test]
+    fn parse_crlf_and_cr_lines() {
+        let msgs = Msg::parse_event_stream("id: 1\r\ndata: a\r\n\r\nid: 2\rdata: b\r\rdata: c");
+
+        let ids: Vec<_> = msgs.iter().map(|msg| msg.id.as_deref()).collect();
+        let data: Vec<_> = msgs.iter().map(|msg| msg.data.as_deref()).collect();
+
+        assert_eq!(ids, [Some("1"), Some("2"), None]);
+        assert_eq!(data, [Some("a"), Some("b"), Some("c")]);
+    }
+
+    #[test]
+    fn parse_discards_blocks_without_data() {
+        let msgs =
+            Msg::parse_event_stream(": just a comment\n\nid: 1\n\nretry: 10\n\n: note\ndata: x\n");
+
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].id, None);
+        assert_eq!(msgs[0].retry, None);
+        assert_eq!(msgs[0].comment, Some(vec!["note".to_string()]));
+        assert_eq!(msgs[0].data.as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn parse_empty_id_is_no_id() {
+        let msgs = Msg::parse_event_stream("id\ndata: a\n\nid:\ndata: b\n\nid: \0\ndata: c\n\n");
+
+        assert!(msgs.iter().all(|msg| msg.id.is_none()));
+        assert_eq!(msgs.len(), 3);
+    }
+}
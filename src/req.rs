@@ -1,12 +1,50 @@
-use mlua::LuaSerdeExt as _;
-use std::{collections::HashMap, net::SocketAddr};
-
-use crate::{msg::Msg, state::AppState};
-
-#[derive(Debug, Clone)]
+use mlua::{FromLua as _, LuaSerdeExt as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    path::Path,
+};
+use tokio_util::task::task_tracker::TaskTrackerToken;
+
+use crate::{history::Position, msg::Msg, state::AppState};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Addr {
     ip: String,
     port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+impl Addr {
+    /// The address of a client connected to the Unix domain socket at the
+    /// given path.
+    ///
+    /// Unix peers do not have an IP address, so the `ip` is `"unix"`, the
+    /// `port` is 0, and the `path` is the path of the server's socket.
+    pub fn unix(path: &Path) -> Self {
+        Addr {
+            ip: "unix".to_string(),
+            port: 0,
+            path: Some(path.to_string_lossy().into_owned()),
+        }
+    }
+
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "unix:{path}"),
+            None if self.ip.contains(':') => write!(f, "[{}]:{}", self.ip, self.port),
+            None => write!(f, "{}:{}", self.ip, self.port),
+        }
+    }
 }
 
 impl From<SocketAddr> for Addr {
@@ -14,6 +52,7 @@ impl From<SocketAddr> for Addr {
         Addr {
             ip: addr.ip().to_string(),
             port: addr.port(),
+            path: None,
         }
     }
 }
@@ -24,6 +63,7 @@ impl mlua::FromLua for Addr {
             Some(tbl) => Ok(Addr {
                 ip: tbl.get("ip")?,
                 port: tbl.get("port")?,
+                path: tbl.get("path")?,
             }),
             None => Err(mlua::Error::FromLuaConversionError {
                 from: val.type_name(),
@@ -40,12 +80,13 @@ impl mlua::IntoLua for Addr {
 
         tbl.set("ip", self.ip)?;
         tbl.set("port", self.port)?;
+        tbl.set("path", self.path)?;
 
         lua.to_value(&tbl)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Req {
     addr: Addr,
     method: String,
@@ -56,9 +97,9 @@ pub struct Req {
 }
 
 impl Req {
-    pub fn new(addr: SocketAddr, req: &axum::extract::Request) -> Self {
+    pub fn new(addr: Addr, req: &axum::extract::Request) -> Self {
         Req {
-            addr: addr.into(),
+            addr,
             method: req.method().to_string(),
             uri: req.uri().to_string(),
             path: req.uri().path().to_string(),
@@ -78,6 +119,23 @@ impl Req {
         }
     }
 
+    /// The request of a message published by the Lua script, which has the
+    /// address `script` and no headers.
+    pub fn script(path: &str) -> Self {
+        Req {
+            addr: Addr {
+                ip: "script".to_string(),
+                port: 0,
+                path: None,
+            },
+            method: "POST".to_string(),
+            uri: path.to_string(),
+            path: path.to_string(),
+            query: String::new(),
+            headers: HashMap::new(),
+        }
+    }
+
     pub fn addr(&self) -> &Addr {
         &self.addr
     }
@@ -90,9 +148,54 @@ impl Req {
         &self.uri
     }
 
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     pub fn headers(&self) -> &HashMap<String, String> {
         &self.headers
     }
+
+    /// The address, method and path of the request, without its query and
+    /// headers that may hold credentials.
+    pub fn without_credentials(&self) -> Self {
+        Req {
+            addr: self.addr.clone(),
+            method: self.method.clone(),
+            uri: self.path.clone(),
+            path: self.path.clone(),
+            query: String::new(),
+            headers: HashMap::new(),
+        }
+    }
+
+    /// The request with the values of the headers that hold credentials
+    /// replaced, to show it to someone other than its client.
+    pub fn redacted(&self) -> Self {
+        let sensitive = [
+            http::header::AUTHORIZATION,
+            http::header::COOKIE,
+            http::header::PROXY_AUTHORIZATION,
+        ];
+
+        Req {
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    if sensitive
+                        .iter()
+                        .any(|s| name.eq_ignore_ascii_case(s.as_str()))
+                    {
+                        (name.clone(), "[redacted]".to_string())
+                    } else {
+                        (name.clone(), value.clone())
+                    }
+                })
+                .collect(),
+            ..self.clone()
+        }
+    }
 }
 
 impl mlua::FromLua for Req {
@@ -134,15 +237,20 @@ impl mlua::IntoLua for Req {
 pub struct PubReq {
     req: Req,
     msg: Msg,
-    meta: Option<mlua::Table>,
+    topics: Vec<String>,
+    meta: Option<Meta>,
+    /// The position of the message in the history, once it is recorded
+    position: Option<Position>,
 }
 
 impl PubReq {
-    pub fn new(req: Req, msg: Msg) -> Self {
+    pub fn new(req: Req, msg: Msg, topics: Vec<String>) -> Self {
         Self {
             req,
             msg,
+            topics: normalize_topics(topics),
             meta: None,
+            position: None,
         }
     }
 
@@ -154,8 +262,28 @@ impl PubReq {
         &self.msg
     }
 
+    pub fn topics(&self) -> &[String] {
+        &self.topics
+    }
+
     pub fn meta(&self) -> Option<&mlua::Table> {
-        self.meta.as_ref()
+        self.meta.as_ref().and_then(Meta::table)
+    }
+
+    pub fn position(&self) -> Option<Position> {
+        self.position
+    }
+
+    pub fn with_position(self, position: Option<Position>) -> Self {
+        Self { position, ..self }
+    }
+
+    /// Detaches the fields set by the script, as `Meta::detach` does.
+    pub fn detach(self) -> mlua::Result<Self> {
+        Ok(Self {
+            meta: self.meta.map(Meta::detach).transpose()?,
+            ..self
+        })
     }
 }
 
@@ -169,10 +297,15 @@ impl mlua::FromLua for PubReq {
                 let msg = tbl.get("msg")?;
                 tbl.set("msg", mlua::Value::Nil)?;
 
+                let topics = tbl.get::<Option<Vec<String>>>("topics")?;
+                tbl.set("topics", mlua::Value::Nil)?;
+
                 Ok(Self {
                     req,
                     msg,
-                    meta: Some(tbl.to_owned()),
+                    topics: normalize_topics(topics.unwrap_or_default()),
+                    meta: Some(Meta::Table(tbl.to_owned())),
+                    position: None,
                 })
             }
             None => Err(mlua::Error::FromLuaConversionError {
@@ -187,12 +320,13 @@ impl mlua::FromLua for PubReq {
 impl mlua::IntoLua for PubReq {
     fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
         let tbl = match self.meta {
-            Some(tbl) => tbl,
+            Some(meta) => meta.into_table(lua)?,
             None => lua.create_table()?,
         };
 
         tbl.set("req", self.req)?;
         tbl.set("msg", self.msg)?;
+        tbl.set("topics", topics_to_lua(lua, self.topics)?)?;
 
         lua.to_value(&tbl)
     }
@@ -200,21 +334,75 @@ impl mlua::IntoLua for PubReq {
 
 #[derive(Debug, Clone)]
 pub struct SubReq {
+    id: String,
     req: Req,
-    meta: Option<mlua::Table>,
+    topics: Vec<String>,
+    meta: Option<Meta>,
 }
 
 impl SubReq {
-    pub fn new(req: Req) -> Self {
-        Self { req, meta: None }
+    pub fn new(req: Req, topics: Vec<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            req,
+            topics: normalize_topics(topics),
+            meta: None,
+        }
+    }
+
+    /// The id that the script can address the subscriber by, which is a
+    /// random UUID unless the `subscribe` function sets another.
+    pub fn id(&self) -> &str {
+        &self.id
     }
 
     pub fn req(&self) -> &Req {
         &self.req
     }
 
+    pub fn topics(&self) -> &[String] {
+        &self.topics
+    }
+
     pub fn meta(&self) -> Option<&mlua::Table> {
-        self.meta.as_ref()
+        self.meta.as_ref().and_then(Meta::table)
+    }
+
+    /// The fields set by the script as JSON, without the fields of the
+    /// request itself.  Values that cannot be encoded, such as functions,
+    /// are `null`.
+    pub fn meta_json(&self) -> serde_json::Value {
+        let mut value = match &self.meta {
+            Some(Meta::Table(tbl)) => serde_json::to_value(
+                mlua::Value::Table(tbl.clone())
+                    .to_serializable()
+                    .deny_unsupported_types(false),
+            )
+            .unwrap_or_default(),
+            Some(Meta::Json(value)) => value.clone(),
+            None => serde_json::Value::Null,
+        };
+
+        // The script's table gets them back when it is passed to a function,
+        // except for an id that does not address the subscriber
+        if let Some(fields) = value.as_object_mut() {
+            if fields.get("id").and_then(|id| id.as_str()) == Some(self.id.as_str()) {
+                fields.remove("id");
+            }
+            for key in ["req", "topics"] {
+                fields.remove(key);
+            }
+        }
+
+        value
+    }
+
+    /// Detaches the fields set by the script, as `Meta::detach` does.
+    pub fn detach(self) -> mlua::Result<Self> {
+        Ok(Self {
+            meta: self.meta.map(Meta::detach).transpose()?,
+            ..self
+        })
     }
 }
 
@@ -222,12 +410,30 @@ impl mlua::FromLua for SubReq {
     fn from_lua(val: mlua::Value, _lua: &mlua::Lua) -> mlua::Result<Self> {
         match val.as_table() {
             Some(tbl) => {
+                // Only a string or a number can address the subscriber, and
+                // any other value stays with the fields set by the script
+                let id = match tbl.get::<mlua::Value>("id")? {
+                    mlua::Value::String(id) => Some(id.to_str()?.to_string()),
+                    id @ (mlua::Value::Integer(_) | mlua::Value::Number(_)) => {
+                        Some(id.to_string()?)
+                    }
+                    _ => None,
+                };
+                if id.is_some() {
+                    tbl.set("id", mlua::Value::Nil)?;
+                }
+
                 let req = tbl.get("req")?;
                 tbl.set("req", mlua::Value::Nil)?;
 
+                let topics = tbl.get::<Option<Vec<String>>>("topics")?;
+                tbl.set("topics", mlua::Value::Nil)?;
+
                 Ok(Self {
+                    id: id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
                     req,
-                    meta: Some(tbl.to_owned()),
+                    topics: normalize_topics(topics.unwrap_or_default()),
+                    meta: Some(Meta::Table(tbl.to_owned())),
                 })
             }
             None => Err(mlua::Error::FromLuaConversionError {
@@ -242,35 +448,126 @@ impl mlua::FromLua for SubReq {
 impl mlua::IntoLua for SubReq {
     fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
         let tbl = match self.meta {
-            Some(tbl) => tbl,
+            Some(meta) => meta.into_table(lua)?,
             None => lua.create_table()?,
         };
+        if !tbl.contains_key("id")? {
+            tbl.set("id", self.id)?;
+        }
         tbl.set("req", self.req)?;
+        tbl.set("topics", topics_to_lua(lua, self.topics)?)?;
 
         lua.to_value(&tbl)
     }
 }
 
-pub struct SubReqGuard<'a> {
-    state: &'a AppState,
+/// The fields that the script set on a request table.
+#[derive(Debug, Clone)]
+enum Meta {
+    /// The table in the Lua state of the script worker that returned it.
+    Table(mlua::Table),
+    /// A copy of the fields that can be passed to any worker.
+    Json(serde_json::Value),
+}
+
+impl Meta {
+    fn table(&self) -> Option<&mlua::Table> {
+        match self {
+            Meta::Table(tbl) => Some(tbl),
+            Meta::Json(_) => None,
+        }
+    }
+
+    /// Copies the table out of the Lua state of the worker that returned
+    /// it, so that the request can be passed to any worker of the script.
+    /// The copy is made without locking another Lua state, so that workers
+    /// never wait on each other.
+    fn detach(self) -> mlua::Result<Self> {
+        match self {
+            Meta::Table(tbl) => serde_json::to_value(&tbl)
+                .map(Meta::Json)
+                .map_err(mlua::Error::external),
+            json => Ok(json),
+        }
+    }
+
+    fn into_table(self, lua: &mlua::Lua) -> mlua::Result<mlua::Table> {
+        match self {
+            Meta::Table(tbl) => Ok(tbl),
+            Meta::Json(value) => mlua::Table::from_lua(lua.to_value(&value)?, lua),
+        }
+    }
+}
+
+/// Removes empty and duplicate topics while preserving their order.
+fn normalize_topics(topics: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+
+    topics
+        .into_iter()
+        .filter(|topic| !topic.is_empty() && seen.insert(topic.clone()))
+        .collect()
+}
+
+fn topics_to_lua(lua: &mlua::Lua, topics: Vec<String>) -> mlua::Result<mlua::Table> {
+    let tbl = lua.create_sequence_from(topics)?;
+    tbl.set_metatable(Some(lua.array_metatable()));
+    Ok(tbl)
+}
+
+pub struct SubReqGuard {
+    state: AppState,
     sub_req: SubReq,
+    // Keeps a graceful shutdown waiting until the subscriber is gone
+    _task: TaskTrackerToken,
 }
 
-impl<'a> SubReqGuard<'a> {
-    pub fn new(state: &'a AppState, sub_req: SubReq) -> Self {
-        Self { state, sub_req }
+impl SubReqGuard {
+    pub fn new(state: &AppState, sub_req: SubReq) -> Self {
+        state.metrics.subscribed();
+        Self {
+            state: state.clone(),
+            sub_req,
+            _task: state.tasks.token(),
+        }
     }
 }
 
-impl Drop for SubReqGuard<'_> {
+impl Drop for SubReqGuard {
     fn drop(&mut self) {
+        self.state.metrics.unsubscribed();
+
         let state = self.state.clone();
         let sub_req = self.sub_req.clone();
 
-        tokio::spawn(async move {
+        self.state.tasks.spawn(async move {
             if let Err(e) = state.script.unsubscribe(&sub_req).await {
                 tracing::error!("{e}");
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_hides_credentials() {
+        let req = axum::extract::Request::builder()
+            .uri("/sse?topic=a")
+            .header("authorization", "Bearer secret")
+            .header("cookie", "session=secret")
+            .header("proxy-authorization", "Basic secret")
+            .header("user-agent", "curl")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let req = Req::new(Addr::from(SocketAddr::from(([127, 0, 0, 1], 1234))), &req).redacted();
+
+        for name in ["authorization", "cookie", "proxy-authorization"] {
+            assert_eq!(req.headers()[name], "[redacted]");
+        }
+        assert_eq!(req.headers()["user-agent"], "curl");
+        assert_eq!(req.uri(), "/sse?topic=a");
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+
+use crate::cli::Cli;
+
+/// Characters that make a shell do more than run a program with arguments.
+const SHELL_OPERATORS: &[char] = &[';', '&', '|', '$', '`', '<', '>', '(', ')', '\n', '\r'];
+
+/// The capabilities of a Lua script run with `--script-sandbox`.
+///
+/// Files, HTTP hosts, environment variables and commands are only
+/// accessible to the script when they are allowed by the `--script-allow-*`
+/// options, and any other access raises a Lua error.
+///
+/// The sandbox is kept in the app data of the Lua states so that the
+/// built-in packages can check their own accesses.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    paths: Vec<PathBuf>,
+    hosts: Vec<String>,
+    env: Vec<String>,
+    exec: Vec<String>,
+}
+
+impl Sandbox {
+    pub fn from_cli(cli: &Cli) -> anyhow::Result<Self> {
+        Ok(Self {
+            paths: cli
+                .script_allow_path
+                .iter()
+                .map(|path| {
+                    fs::canonicalize(path).with_context(|| format!("allow path {}", path.display()))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            hosts: cli
+                .script_allow_host
+                .iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+            env: cli.script_allow_env.clone(),
+            exec: cli.script_allow_exec.clone(),
+        })
+    }
+
+    /// Restricts the standard library of a Lua state to the sandbox.
+    pub fn apply(&self, lua: &mlua::Lua) -> mlua::Result<()> {
+        lua.set_app_data(self.clone());
+
+        // The functions return the error of an access that is not allowed
+        // for the script to raise it at the caller
+        let allow = lua.create_table()?;
+        let sandbox = self.clone();
+        allow.set(
+            "path",
+            lua.create_function(move |_lua, path: String| Ok(sandbox.allow_path(&path).err()))?,
+        )?;
+        let sandbox = self.clone();
+        allow.set(
+            "env",
+            lua.create_function(move |_lua, name: String| Ok(sandbox.allow_env(&name).err()))?,
+        )?;
+        let sandbox = self.clone();
+        allow.set(
+            "exec",
+            lua.create_function(move |_lua, command: String| {
+                Ok(sandbox.allow_exec(&command).err())
+            })?,
+        )?;
+
+        lua.load(include_str!("lua/sandbox.lua"))
+            .set_name("src/lua/sandbox.lua")
+            .call::<()>(allow)
+    }
+
+    /// Whether a file or directory is in one of the allowed paths.
+    pub fn allow_path<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+
+        match resolve(path) {
+            Ok(resolved) if self.paths.iter().any(|p| resolved.starts_with(p)) => Ok(()),
+            _ => Err(format!(
+                "access to path {:?} is not allowed by the script sandbox",
+                path.display().to_string()
+            )),
+        }
+    }
+
+    /// Whether a URL is on one of the allowed hosts, given as a domain name
+    /// (`example.com`), a wildcard for its subdomains (`*.example.com`) or
+    /// an IP address.
+    pub fn allow_url(&self, url: &url::Url) -> Result<(), String> {
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+
+        let allowed = self
+            .hosts
+            .iter()
+            .any(|allowed| match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => *allowed == host,
+            });
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!(
+                "HTTP requests to host {host:?} are not allowed by the script sandbox"
+            ))
+        }
+    }
+
+    /// Whether an environment variable is allowed.
+    pub fn allow_env(&self, name: &str) -> Result<(), String> {
+        if self.env.iter().any(|allowed| allowed == name) {
+            Ok(())
+        } else {
+            Err(format!(
+                "environment variable {name:?} is not allowed by the script sandbox"
+            ))
+        }
+    }
+
+    /// Whether a shell command runs one of the allowed programs, without
+    /// shell operators that could run others.
+    pub fn allow_exec(&self, command: &str) -> Result<(), String> {
+        let program = command.split_whitespace().next().unwrap_or_default();
+
+        if !command.contains(SHELL_OPERATORS) && self.exec.iter().any(|allowed| allowed == program)
+        {
+            Ok(())
+        } else {
+            Err(format!(
+                "running command {command:?} is not allowed by the script sandbox"
+            ))
+        }
+    }
+}
+
+/// Checks an access with the sandbox of a Lua state, if it has one.
+pub fn check(
+    lua: &mlua::Lua,
+    allow: impl FnOnce(&Sandbox) -> Result<(), String>,
+) -> mlua::Result<()> {
+    match lua.app_data_ref::<Sandbox>() {
+        Some(sandbox) => allow(&sandbox).map_err(mlua::Error::runtime),
+        None => Ok(()),
+    }
+}
+
+/// The absolute path of a file without symbolic links, which may not exist
+/// yet as when it is opened for writing.
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let name = path.file_name().ok_or(e)?;
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            Ok(fs::canonicalize(parent)?.join(name))
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> url::Url {
+        url::Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn allow_url_wildcard_subdomains() {
+        let sandbox = Sandbox {
+            hosts: vec!["*.example.com".to_string()],
+            ..Default::default()
+        };
+
+        assert!(sandbox.allow_url(&url("https://api.example.com/")).is_ok());
+        assert!(sandbox.allow_url(&url("https://a.b.EXAMPLE.com/")).is_ok());
+        assert!(sandbox.allow_url(&url("https://example.com/")).is_err());
+        assert!(sandbox.allow_url(&url("https://evilexample.com/")).is_err());
+        assert!(
+            sandbox
+                .allow_url(&url("https://example.com.evil.org/"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn allow_url_exact_host() {
+        let sandbox = Sandbox {
+            hosts: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+
+        assert!(
+            sandbox
+                .allow_url(&url("http://example.com:8080/path"))
+                .is_ok()
+        );
+        assert!(sandbox.allow_url(&url("https://evilexample.com/")).is_err());
+        assert!(sandbox.allow_url(&url("https://api.example.com/")).is_err());
+    }
+
+    #[test]
+    fn allow_exec_shell_operators() {
+        let sandbox = Sandbox {
+            exec: vec!["echo".to_string()],
+            ..Default::default()
+        };
+
+        assert!(sandbox.allow_exec("echo hello").is_ok());
+        assert!(sandbox.allow_exec("  echo").is_ok());
+        assert!(sandbox.allow_exec("cat /etc/passwd").is_err());
+        assert!(sandbox.allow_exec("echoes").is_err());
+        assert!(sandbox.allow_exec("").is_err());
+
+        for command in [
+            "echo; rm -rf /",
+            "echo && rm -rf /",
+            "echo | sh",
+            "echo $(rm -rf /)",
+            "echo `rm -rf /`",
+            "echo > /etc/passwd",
+            "echo < /etc/passwd",
+            "echo\nrm -rf /",
+        ] {
+            assert!(sandbox.allow_exec(command).is_err(), "{command:?}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_missing_file_in_symlinked_dir() {
+        let dir = std::env::temp_dir().join(format!("tinysse-sandbox-{}", std::process::id()));
+        let real = dir.join("real");
+        let link = dir.join("link");
+        fs::create_dir_all(&real).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let real = fs::canonicalize(&real).unwrap();
+        let resolved = resolve(&link.join("new.txt"));
+        let sandbox = Sandbox {
+            paths: vec![real.clone()],
+            ..Default::default()
+        };
+        let allowed = sandbox.allow_path(link.join("new.txt"));
+        let escaped = sandbox.allow_path(link.join("..").join("other.txt"));
+        let missing_dir = resolve(&link.join("missing").join("new.txt"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(resolved.unwrap(), real.join("new.txt"));
+        assert!(allowed.is_ok());
+        assert!(escaped.is_err());
+        assert!(missing_dir.is_err());
+    }
+}
@@ -1,38 +1,130 @@
-use std::{fs, path::Path, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    future::Future as _,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use mlua::{HookTriggers, IntoLua as _, VmState};
 
 use crate::{
-    cli::Cli,
+    cli::{Cli, TimeoutPolicy},
+    metrics::Metrics,
     msg::Msg,
     req::{PubReq, SubReq},
+    sandbox::Sandbox,
+    state::AppState,
     userdata,
 };
 
+/// The functions of the script that the server calls.
+pub const HOOKS: &[&str] = &[
+    "startup",
+    "tick",
+    "publish",
+    "subscribe",
+    "catchup",
+    "message",
+    "unsubscribe",
+    "timeout",
+    "lagged",
+    "shutdown",
+    "reload",
+];
+
+/// How many instructions a script function with a timeout runs between
+/// checks of its deadline.
+const DEADLINE_INSTRUCTIONS: u32 = 10_000;
+
+/// The Lua script, run by one or more workers.
+///
+/// Every worker is an isolated Lua state loaded from the same script.  The
+/// hooks of subscribers and publishers are called on the workers in turn,
+/// while `startup`, `tick`, `shutdown` and `reload` are called on every
+/// worker.  The workers only share the `store` and `server` packages.
 #[derive(Debug, Clone)]
 pub struct Script {
-    lua: mlua::Lua,
+    workers: Arc<[mlua::Lua]>,
+    next: Arc<AtomicUsize>,
+    metrics: Metrics,
+    store: userdata::Store,
+    server: userdata::Server,
+    timeouts: Arc<Timeouts>,
 }
 
 impl Script {
-    pub fn new() -> Self {
-        let script = Self {
-            lua: mlua::Lua::new(),
-        };
-        script.init();
-        script
+    pub fn new(metrics: Metrics, workers: usize) -> Self {
+        Self::with_workers(metrics, (0..workers).map(|_| mlua::Lua::new()).collect())
+    }
+
+    pub fn unsafe_new(metrics: Metrics, workers: usize) -> Self {
+        Self::with_workers(
+            metrics,
+            (0..workers)
+                // SAFETY: The user has explicitly requested an unsafe Lua context.
+                .map(|_| unsafe { mlua::Lua::unsafe_new() })
+                .collect(),
+        )
     }
 
-    pub fn unsafe_new() -> Self {
+    fn with_workers(metrics: Metrics, workers: Arc<[mlua::Lua]>) -> Self {
+        assert!(!workers.is_empty(), "script needs at least one worker");
+
         let script = Self {
-            // SAFETY: The user has explicitly requested an unsafe Lua context.
-            lua: unsafe { mlua::Lua::unsafe_new() },
+            workers,
+            next: Arc::default(),
+            metrics,
+            store: userdata::Store::default(),
+            server: userdata::Server::default(),
+            timeouts: Arc::default(),
         };
-        script.init();
+
+        for lua in script.workers.iter() {
+            script.init(lua);
+        }
+
         script
     }
 
-    fn init(&self) {
+    /// Limits the time the script functions can run.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = Arc::new(timeouts);
+        self
+    }
+
+    /// Limits the memory every worker can allocate.
+    pub fn with_memory_limit(self, bytes: usize) -> anyhow::Result<Self> {
+        for lua in self.workers.iter() {
+            lua.set_memory_limit(bytes)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Restricts what the script can access to the capabilities of a
+    /// sandbox.
+    pub fn with_sandbox(self, sandbox: &Sandbox) -> anyhow::Result<Self> {
+        for lua in self.workers.iter() {
+            sandbox.apply(lua)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Attaches the state of the server to the `server` package once it is
+    /// built, which includes the script itself.
+    pub fn attach(&self, state: &Arc<AppState>) {
+        self.server.attach(state);
+    }
+
+    fn init(&self, lua: &mlua::Lua) {
         // Load the built-in libraries and global script
-        let globals = self.lua.globals();
+        let globals = lua.globals();
         let loaded = globals
             .get::<mlua::Table>("package")
             .expect("get package table")
@@ -72,66 +164,218 @@ impl Script {
         loaded
             .set("template", userdata::Template {})
             .expect("set userdata template");
+        // The packages shared by the workers keep their state in the app
+        // data, where their functions look it up
+        lua.set_app_data(userdata::Metrics(self.metrics.clone()));
+        lua.set_app_data(self.store.clone());
+        lua.set_app_data(self.server.clone());
+        loaded
+            .set("metrics", userdata::Metrics(self.metrics.clone()))
+            .expect("set userdata metrics");
+        loaded
+            .set("store", self.store.clone())
+            .expect("set userdata store");
+        loaded
+            .set("server", self.server.clone())
+            .expect("set userdata server");
+
+        // Arm the deadline of a script function on the coroutines it resumes
+        let arm = lua
+            .create_function(|lua, thread: mlua::Value| {
+                if let mlua::Value::Thread(thread) = thread {
+                    arm_deadline(lua, &thread);
+                }
+                Ok(())
+            })
+            .expect("create deadline function");
+        lua.load(include_str!("lua/deadline.lua"))
+            .set_name("src/lua/deadline.lua")
+            .call::<()>(arm)
+            .expect("load and call src/lua/deadline.lua");
 
-        self.lua
-            .load(include_str!("lua/global.lua"))
+        lua.load(include_str!("lua/global.lua"))
             .set_name("src/lua/global.lua")
             .exec()
             .expect("load and exec src/lua/global.lua");
     }
 
     pub async fn load_path<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<&Self> {
-        self.lua
-            .load(fs::read_to_string(path.as_ref())?)
-            .set_name(path.as_ref().to_string_lossy())
-            .exec_async()
-            .await?;
+        let source = fs::read_to_string(path.as_ref())?;
+
+        for lua in self.workers.iter() {
+            lua.load(source.as_str())
+                .set_name(path.as_ref().to_string_lossy())
+                .exec_async()
+                .await?;
+        }
+
         Ok(self)
     }
 
-    // Store the callback functions in the Lua registry for faster access
+    /// Reloads the script from a file while the subscribers stay connected.
+    ///
+    /// The script runs in a new environment that reads the current globals,
+    /// so that a syntax or runtime error leaves the current functions in
+    /// place.  Once it has run on every worker, its globals replace the
+    /// current ones, the functions it no longer defines are reset to their
+    /// defaults, and the `reload(old)` function is called with the replaced
+    /// values.
+    pub async fn reload<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let source = fs::read_to_string(path.as_ref())?;
+        let mut envs = Vec::with_capacity(self.workers.len());
+
+        for lua in self.workers.iter() {
+            let env = environment(lua)?;
+
+            lua.load(source.as_str())
+                .set_name(path.as_ref().to_string_lossy())
+                .set_environment(env.clone())
+                .exec_async()
+                .await?;
+
+            envs.push(env);
+        }
+
+        for (lua, env) in self.workers.iter().zip(envs) {
+            let defaults = environment(lua)?;
+
+            lua.load(include_str!("lua/global.lua"))
+                .set_name("src/lua/global.lua")
+                .set_environment(defaults.clone())
+                .exec()?;
+
+            // Replace the globals in a single call so that no other function
+            // runs with only some of them replaced
+            let old = lua
+                .load(include_str!("lua/reload.lua"))
+                .set_name("src/lua/reload.lua")
+                .call::<mlua::Table>((env, defaults))?;
+
+            register(lua);
+
+            if let Some(func) = hook(lua, "reload") {
+                let open = self.open("reload", || ());
+                self.call::<()>("reload", (lua, func), old, open).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn register(&self) {
-        let globals = self.lua.globals();
-
-        for name in &[
-            "tick",
-            "publish",
-            "subscribe",
-            "catchup",
-            "message",
-            "unsubscribe",
-            "timeout",
-        ] {
-            if let Ok(func) = globals.get::<mlua::Function>(*name) {
-                self.lua
-                    .set_named_registry_value(name, func)
-                    .unwrap_or_else(|_| panic!("set registry function value {name}"));
+        for lua in self.workers.iter() {
+            register(lua);
+        }
+    }
+
+    /// Whether the script defines a hook, rather than leaving the default
+    /// function of `src/lua/global.lua` in place.
+    pub fn defines(&self, name: &str) -> bool {
+        hook(&self.workers[0], name).is_some_and(|func| {
+            func.info()
+                .source
+                .is_none_or(|source| !source.ends_with("src/lua/global.lua"))
+        })
+    }
+
+    /// The registered callback function of a hook, on the next worker in
+    /// turn.
+    fn hook(&self, name: &str) -> Option<(&mlua::Lua, mlua::Function)> {
+        let worker = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        let lua = &self.workers[worker];
+        hook(lua, name).map(|func| (lua, func))
+    }
+
+    /// Detaches a request returned by a worker when there are several, so
+    /// that the next hooks can be called on the other workers.
+    fn detach<T>(&self, req: T, detach: fn(T) -> mlua::Result<T>) -> mlua::Result<T> {
+        if self.workers.len() > 1 {
+            detach(req)
+        } else {
+            Ok(req)
+        }
+    }
+
+    /// The value to carry on with if a hook times out, if its timeout policy
+    /// is open.
+    fn open<R>(&self, hook: &str, value: impl FnOnce() -> R) -> Option<R> {
+        (self.timeouts.timeout(hook).is_some() && self.timeouts.policy(hook) == TimeoutPolicy::Open)
+            .then(value)
+    }
+
+    /// Calls a script function, recording the duration of the call.
+    ///
+    /// If the function times out, the `open` value is returned instead of
+    /// the error when the timeout policy of the hook is open.
+    async fn call<R: mlua::FromLuaMulti>(
+        &self,
+        hook: &'static str,
+        (lua, func): (&mlua::Lua, mlua::Function),
+        args: impl mlua::IntoLuaMulti,
+        open: Option<R>,
+    ) -> anyhow::Result<R> {
+        let start = Instant::now();
+        let result = match self.timeouts.timeout(hook) {
+            Some(timeout) => call_timeout(hook, lua, func, args, timeout).await,
+            None => Ok(func.call_async::<R>(args).await?),
+        };
+        self.metrics.hook(hook, start.elapsed());
+
+        match (result, open) {
+            (Err(e), open) if e.is::<HookTimeout>() => {
+                self.metrics.hook_timeout(hook);
+
+                match open {
+                    Some(value) => {
+                        tracing::warn!("{e}, carrying on without it");
+                        Ok(value)
+                    }
+                    None => Err(e),
+                }
             }
+            (result, _) => result,
         }
     }
 
+    /// Calls `startup(cli)` on every worker, with the number of the worker
+    /// as `cli.script_worker`.
     pub async fn startup(&self, cli: &Cli) -> anyhow::Result<()> {
-        let globals = self.lua.globals();
+        for (worker, lua) in self.workers.iter().enumerate() {
+            if let Ok(func) = lua.globals().get::<mlua::Function>("startup") {
+                let cli = cli.clone().into_lua(lua)?;
+
+                if let mlua::Value::Table(tbl) = &cli {
+                    tbl.set("script_worker", worker + 1)?;
+                }
 
-        if let Ok(func) = globals.get::<mlua::Function>("startup") {
-            func.call_async::<()>(cli.clone()).await?;
+                let open = self.open("startup", || ());
+                self.call::<()>("startup", (lua, func), cli, open).await?;
+            }
         }
 
         Ok(())
     }
 
     pub async fn tick(&self, count: usize) -> anyhow::Result<()> {
-        if let Ok(func) = self.lua.named_registry_value::<mlua::Function>("tick") {
-            func.call_async::<()>(count).await?;
+        for lua in self.workers.iter() {
+            if let Some(func) = hook(lua, "tick") {
+                let open = self.open("tick", || ());
+                self.call::<()>("tick", (lua, func), count, open).await?;
+            }
         }
 
         Ok(())
     }
 
     pub async fn publish(&self, pub_req: PubReq) -> anyhow::Result<Option<PubReq>> {
-        if let Ok(func) = self.lua.named_registry_value::<mlua::Function>("publish") {
-            if let Some(pub_req) = func.call_async::<Option<PubReq>>(pub_req).await? {
-                Ok(Some(pub_req))
+        if let Some(hook) = self.hook("publish") {
+            let open = self.open("publish", || Some(pub_req.clone()));
+
+            if let Some(pub_req) = self
+                .call::<Option<PubReq>>("publish", hook, pub_req, open)
+                .await?
+            {
+                Ok(Some(self.detach(pub_req, PubReq::detach)?))
             } else {
                 Ok(None)
             }
@@ -141,9 +385,14 @@ impl Script {
     }
 
     pub async fn subscribe(&self, sub_req: SubReq) -> anyhow::Result<Option<SubReq>> {
-        if let Ok(func) = self.lua.named_registry_value::<mlua::Function>("subscribe") {
-            if let Some(sub_req) = func.call_async::<Option<SubReq>>(sub_req).await? {
-                Ok(Some(sub_req))
+        if let Some(hook) = self.hook("subscribe") {
+            let open = self.open("subscribe", || Some(sub_req.clone()));
+
+            if let Some(sub_req) = self
+                .call::<Option<SubReq>>("subscribe", hook, sub_req, open)
+                .await?
+            {
+                Ok(Some(self.detach(sub_req, SubReq::detach)?))
             } else {
                 Ok(None)
             }
@@ -157,10 +406,12 @@ impl Script {
         sub_req: &SubReq,
         last_event_id: Option<String>,
     ) -> anyhow::Result<Option<Vec<Msg>>> {
-        if let Ok(func) = self.lua.named_registry_value::<mlua::Function>("catchup") {
-            return Ok(func
-                .call_async::<Option<Vec<Msg>>>((sub_req.clone(), last_event_id))
-                .await?);
+        if let Some(hook) = self.hook("catchup") {
+            let open = self.open("catchup", || None);
+
+            return self
+                .call::<Option<Vec<Msg>>>("catchup", hook, (sub_req.clone(), last_event_id), open)
+                .await;
         }
 
         Ok(None)
@@ -171,26 +422,21 @@ impl Script {
         pub_req: PubReq,
         sub_req: &SubReq,
     ) -> anyhow::Result<Option<PubReq>> {
-        if let Ok(func) = self.lua.named_registry_value::<mlua::Function>("message") {
-            if let Some(pub_req) = func
-                .call_async::<Option<PubReq>>((pub_req, sub_req.to_owned()))
-                .await?
-            {
-                Ok(Some(pub_req))
-            } else {
-                Ok(None)
-            }
+        if let Some(hook) = self.hook("message") {
+            let open = self.open("message", || Some(pub_req.clone()));
+
+            self.call::<Option<PubReq>>("message", hook, (pub_req, sub_req.to_owned()), open)
+                .await
         } else {
             Ok(Some(pub_req))
         }
     }
 
     pub async fn unsubscribe(&self, sub_req: &SubReq) -> anyhow::Result<()> {
-        if let Ok(func) = self
-            .lua
-            .named_registry_value::<mlua::Function>("unsubscribe")
-        {
-            func.call_async::<()>(sub_req.clone()).await?;
+        if let Some(hook) = self.hook("unsubscribe") {
+            let open = self.open("unsubscribe", || ());
+            self.call::<()>("unsubscribe", hook, sub_req.clone(), open)
+                .await?;
         }
 
         Ok(())
@@ -201,18 +447,370 @@ impl Script {
         sub_req: &SubReq,
         elapsed: &Duration,
     ) -> anyhow::Result<Option<f64>> {
-        if let Ok(func) = self.lua.named_registry_value::<mlua::Function>("timeout") {
-            return Ok(func
-                .call_async::<Option<f64>>((sub_req.clone(), elapsed.as_millis()))
-                .await?);
+        if let Some(hook) = self.hook("timeout") {
+            let open = self.open("timeout", || None);
+
+            return self
+                .call::<Option<f64>>(
+                    "timeout",
+                    hook,
+                    (sub_req.clone(), elapsed.as_millis()),
+                    open,
+                )
+                .await;
+        }
+
+        Ok(None)
+    }
+
+    pub async fn lagged(&self, sub_req: &SubReq, missed: u64) -> anyhow::Result<Option<String>> {
+        if let Some(hook) = self.hook("lagged") {
+            let open = self.open("lagged", || None);
+
+            return self
+                .call::<Option<String>>("lagged", hook, (sub_req.clone(), missed), open)
+                .await;
         }
 
         Ok(None)
     }
+
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        for lua in self.workers.iter() {
+            if let Some(func) = hook(lua, "shutdown") {
+                let open = self.open("shutdown", || ());
+                self.call::<()>("shutdown", (lua, func), (), open).await?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for Script {
     fn default() -> Self {
-        Self::new()
+        Self::new(Metrics::default(), 1)
+    }
+}
+
+/// A new environment for a chunk that reads the globals and keeps its own
+/// assignments.
+fn environment(lua: &mlua::Lua) -> mlua::Result<mlua::Table> {
+    let env = lua.create_table()?;
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.globals())?;
+    env.set_metatable(Some(meta));
+    Ok(env)
+}
+
+// Store the callback functions in the Lua registry for faster access.
+// They are stored in a single table so that a reload swaps them at once.
+fn register(lua: &mlua::Lua) {
+    let globals = lua.globals();
+    let hooks = lua.create_table().expect("create hooks table");
+
+    for name in HOOKS {
+        if let Ok(func) = globals.get::<mlua::Function>(*name) {
+            hooks
+                .set(*name, func)
+                .unwrap_or_else(|_| panic!("set hook function {name}"));
+        }
+    }
+
+    lua.set_named_registry_value("hooks", hooks)
+        .expect("set registry hooks table");
+}
+
+/// The registered callback function of a hook.
+fn hook(lua: &mlua::Lua, name: &str) -> Option<mlua::Function> {
+    lua.named_registry_value::<mlua::Table>("hooks")
+        .ok()?
+        .get::<Option<mlua::Function>>(name)
+        .ok()?
+}
+
+/// The timeouts of the script functions and what to do when they are
+/// exceeded, from the `--hook-timeout` and `--hook-timeout-policy` options.
+#[derive(Debug, Default)]
+pub struct Timeouts {
+    timeout: Option<Duration>,
+    timeouts: HashMap<String, Duration>,
+    policy: TimeoutPolicy,
+    policies: HashMap<String, TimeoutPolicy>,
+}
+
+impl Timeouts {
+    pub fn from_cli(cli: &Cli) -> Self {
+        let mut timeouts = Self::default();
+
+        for timeout in &cli.hook_timeout {
+            match &timeout.hook {
+                Some(hook) => {
+                    timeouts.timeouts.insert(hook.clone(), timeout.value);
+                }
+                None => timeouts.timeout = Some(timeout.value),
+            }
+        }
+
+        for policy in &cli.hook_timeout_policy {
+            match &policy.hook {
+                Some(hook) => {
+                    timeouts.policies.insert(hook.clone(), policy.value);
+                }
+                None => timeouts.policy = policy.value,
+            }
+        }
+
+        timeouts
+    }
+
+    /// The timeout of a hook, unless it is not limited.
+    fn timeout(&self, hook: &str) -> Option<Duration> {
+        self.timeouts
+            .get(hook)
+            .or(self.timeout.as_ref())
+            .copied()
+            .filter(|timeout| !timeout.is_zero())
+    }
+
+    fn policy(&self, hook: &str) -> TimeoutPolicy {
+        self.policies.get(hook).copied().unwrap_or(self.policy)
+    }
+}
+
+/// The error of a script function that ran longer than its timeout.
+#[derive(Debug)]
+struct HookTimeout {
+    hook: &'static str,
+    timeout: Duration,
+}
+
+impl fmt::Display for HookTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "script function {} timed out after {}",
+            self.hook,
+            humantime::format_duration(self.timeout)
+        )
+    }
+}
+
+impl std::error::Error for HookTimeout {}
+
+/// Calls a script function in a coroutine of its own with a deadline.
+///
+/// A function that yields, such as when it sleeps or makes an HTTP request,
+/// is dropped once the deadline has passed.  One that does not, such as a
+/// loop, is interrupted by an error returned from an instruction count hook.
+async fn call_timeout<R: mlua::FromLuaMulti>(
+    hook: &'static str,
+    lua: &mlua::Lua,
+    func: mlua::Function,
+    args: impl mlua::IntoLuaMulti,
+    timeout: Duration,
+) -> anyhow::Result<R> {
+    let deadline = Deadline {
+        at: Instant::now() + timeout,
+        timed_out: Arc::new(AtomicBool::new(false)),
+    };
+    let thread = lua.create_thread(func.clone())?;
+
+    let result = {
+        let mut call = std::pin::pin!(thread.clone().into_async::<R>(args));
+
+        // The deadline is kept in the app data while the coroutine runs, so
+        // that the coroutines it resumes are armed with it too.  A script
+        // function called from another one on the same worker, such as by
+        // `server.publish`, hands the deadline back once it yields.
+        let resume = std::future::poll_fn(|cx| {
+            let outer = lua.set_app_data(deadline.clone());
+            deadline.arm(&thread);
+            let poll = call.as_mut().poll(cx);
+            match outer {
+                Some(outer) => {
+                    outer.arm(&lua.current_thread());
+                    lua.set_app_data(outer);
+                }
+                None => {
+                    lua.remove_app_data::<Deadline>();
+                }
+            }
+            poll
+        });
+
+        tokio::time::timeout_at(deadline.at.into(), resume).await
+    };
+
+    match result {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(_)) if deadline.timed_out.load(Ordering::Relaxed) => {
+            Err(HookTimeout { hook, timeout }.into())
+        }
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => {
+            // The coroutine still holds the call it was waiting on, along with
+            // any userdata that call borrows, until it is garbage collected.
+            thread.reset(func)?;
+            lua.gc_collect()?;
+
+            Err(HookTimeout { hook, timeout }.into())
+        }
+    }
+}
+
+/// Calls a function in a coroutine of its own, armed with the deadline of
+/// the script function that calls it, if it has one.
+pub async fn call_async<R: mlua::FromLuaMulti>(
+    lua: &mlua::Lua,
+    func: mlua::Function,
+    args: impl mlua::IntoLuaMulti,
+) -> mlua::Result<R> {
+    let thread = lua.create_thread(func)?;
+    let mut call = std::pin::pin!(thread.clone().into_async::<R>(args));
+
+    std::future::poll_fn(|cx| {
+        let caller = lua.current_thread();
+        arm_deadline(lua, &thread);
+        let poll = call.as_mut().poll(cx);
+        arm_deadline(lua, &caller);
+        poll
+    })
+    .await
+}
+
+/// Arms the deadline of the script function that a worker is running, if it
+/// has one, on a coroutine.
+fn arm_deadline(lua: &mlua::Lua, thread: &mlua::Thread) {
+    let deadline = lua
+        .app_data_ref::<Deadline>()
+        .map(|deadline| deadline.clone());
+    if let Some(deadline) = deadline {
+        deadline.arm(thread);
+    }
+}
+
+/// The deadline of a script function call with a timeout.
+#[derive(Clone)]
+struct Deadline {
+    at: Instant,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    /// Sets the hook that interrupts a coroutine once the deadline has passed.
+    ///
+    /// A Lua state has a single thread hook, which does not follow the call
+    /// into the coroutines that it resumes, so the hook is set again on every
+    /// coroutine when it is resumed and on its caller when it yields or
+    /// returns.  Once the deadline has passed, the hook records that the call
+    /// timed out and returns an error at every instruction, so that the
+    /// function cannot carry on by catching it with `pcall`.
+    fn arm(&self, thread: &mlua::Thread) {
+        // Setting the hook restarts its instruction count, so the deadline
+        // is also checked here for a loop that resumes a coroutine
+        if Instant::now() >= self.at {
+            self.timed_out.store(true, Ordering::Relaxed);
+        }
+
+        if self.timed_out.load(Ordering::Relaxed) {
+            thread.set_hook(HookTriggers::new().every_nth_instruction(1), |_, _| {
+                Err(mlua::Error::runtime("timed out"))
+            });
+            return;
+        }
+
+        let deadline = self.clone();
+
+        thread.set_hook(
+            HookTriggers::new().every_nth_instruction(DEADLINE_INSTRUCTIONS),
+            move |lua, _| {
+                if Instant::now() < deadline.at {
+                    return Ok(VmState::Continue);
+                }
+
+                deadline.arm(&lua.current_thread());
+
+                Err(mlua::Error::runtime("timed out"))
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a chunk on a worker of a new script with a short timeout.
+    async fn run<R: mlua::FromLuaMulti>(source: &str) -> anyhow::Result<R> {
+        let script = Script::new(Metrics::new(), 1);
+        let lua = &script.workers[0];
+        let func = lua.load(source).into_function()?;
+        call_timeout("tick", lua, func, (), Duration::from_millis(50)).await
+    }
+
+    fn timed_out(result: anyhow::Result<()>) -> bool {
+        result.is_err_and(|e| e.downcast_ref::<HookTimeout>().is_some())
+    }
+
+    #[tokio::test]
+    async fn test_timeout_interrupts_loops() {
+        assert!(timed_out(run("while true do end").await));
+        assert!(timed_out(run("while true do pcall(error) end").await));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_interrupts_coroutines() {
+        assert!(timed_out(
+            run("coroutine.wrap(function() while true do end end)()").await
+        ));
+        assert!(timed_out(
+            run("coroutine.resume(coroutine.create(function() while true do end end))").await
+        ));
+        assert!(timed_out(
+            run("coroutine.wrap(function() end)() while true do end").await
+        ));
+        assert!(timed_out(
+            run(r#"
+                local co = coroutine.wrap(function() while true do coroutine.yield() end end)
+                while true do co() end
+            "#)
+            .await
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_interrupts_mutex() {
+        assert!(timed_out(
+            run(r#"
+                local mutex = require("mutex")()
+                mutex(function() while true do end end)
+            "#)
+            .await
+        ));
+        assert!(timed_out(
+            run(r#"
+                local mutex = require("mutex")()
+                mutex(function() end)
+                while true do end
+            "#)
+            .await
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_coroutines_keep_their_results() {
+        let values: (i64, i64, bool, i64, i64) = run(r#"
+            local co = coroutine.wrap(function(a)
+                local b = coroutine.yield(a + 1)
+                return b * 2
+            end)
+            local ok, err = coroutine.resume(coroutine.create(function() error({code = 7}) end))
+            local _, wrapped = pcall(coroutine.wrap(function() error({code = 8}) end))
+            return co(1), co(5), ok, err.code, wrapped.code
+        "#)
+        .await
+        .unwrap();
+        assert_eq!(values, (2, 10, false, 7, 8));
     }
 }
@@ -0,0 +1,240 @@
+use std::{io, time::Duration};
+
+use anyhow::Context as _;
+use axum::{
+    Router,
+    body::Body,
+    extract::{ConnectInfo, connect_info::Connected},
+    serve::IncomingStream,
+};
+use hyper::{body::Incoming, service::service_fn};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower::ServiceExt as _;
+
+use crate::{cli::Listen, req::Addr, tls::Tls};
+
+/// How long a client has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A socket the HTTP server listens on.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, std::path::PathBuf),
+}
+
+impl Listener {
+    /// Binds a listener to a TCP address or to a Unix domain socket path.
+    ///
+    /// A Unix domain socket left over by a previous run is replaced, and the
+    /// new socket is given the `unix_socket_mode` permissions if it is set.
+    pub async fn bind(listen: &Listen, unix_socket_mode: Option<u32>) -> anyhow::Result<Self> {
+        match listen {
+            Listen::Tcp(addr) => Ok(Listener::Tcp(
+                TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("listen on {listen}"))?,
+            )),
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+
+                // Only remove the socket if nothing is accepting connections
+                // on it, otherwise binding fails as it would for a TCP port
+                // in use.
+                if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+                    && std::os::unix::net::UnixStream::connect(path).is_err()
+                {
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("remove stale socket {}", path.display()))?;
+                }
+
+                let listener = tokio::net::UnixListener::bind(path)
+                    .with_context(|| format!("listen on {listen}"))?;
+
+                if let Some(mode) = unix_socket_mode {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                        .with_context(|| format!("set permissions of {}", path.display()))?;
+                }
+
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+            #[cfg(not(unix))]
+            Listen::Unix(_) => {
+                let _ = unix_socket_mode;
+                anyhow::bail!("Unix domain sockets are not supported on this platform: {listen}")
+            }
+        }
+    }
+
+    /// The address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<Listen> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Listen::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Listen::Unix(path.clone())),
+        }
+    }
+
+    /// Closes the listener, removing the file of a Unix domain socket.
+    fn close(self) {
+        match self {
+            Listener::Tcp(_) => {}
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                drop(listener);
+
+                if let Err(e) = std::fs::remove_file(&path) {
+                    tracing::warn!("remove socket {}: {e}", path.display());
+                }
+            }
+        }
+    }
+
+    async fn accept(&self) -> io::Result<(Box<dyn Io>, Addr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                Ok((Box::new(stream), remote_addr.into()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), Addr::unix(path)))
+            }
+        }
+    }
+}
+
+/// A connection accepted by a `Listener`.
+trait Io: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Io for T {}
+
+impl Connected<IncomingStream<'_>> for Addr {
+    fn connect_info(target: IncomingStream<'_>) -> Self {
+        target.remote_addr().into()
+    }
+}
+
+/// Serves the application on a listener, over TLS if it is given.
+///
+/// HTTP/1.1 and HTTP/2 are negotiated by ALPN over TLS.  Every connection
+/// provides a `ConnectInfo<Addr>` to the handlers.
+///
+/// When `shutdown` is cancelled, the listener stops accepting connections
+/// and the function returns once the open connections are closed.
+pub async fn serve(
+    listener: Listener,
+    app: Router,
+    tls: Option<Tls>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let listener = match (listener, &tls) {
+        (Listener::Tcp(listener), None) => {
+            return axum::serve(listener, app.into_make_service_with_connect_info::<Addr>())
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await;
+        }
+        (listener, _) => listener,
+    };
+
+    let connections = TaskTracker::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => break,
+        };
+
+        let (stream, remote_addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Errors such as running out of file descriptors are
+                // transient, so back off and keep accepting.
+                tracing::error!("{e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        connections.spawn(serve_stream(
+            stream,
+            app.clone(),
+            tls.clone(),
+            remote_addr,
+            shutdown.clone(),
+        ));
+    }
+
+    listener.close();
+    connections.close();
+    connections.wait().await;
+
+    Ok(())
+}
+
+async fn serve_stream(
+    stream: Box<dyn Io>,
+    app: Router,
+    tls: Option<Tls>,
+    remote_addr: Addr,
+    shutdown: CancellationToken,
+) {
+    let Some(tls) = tls else {
+        return serve_connection(TokioIo::new(stream), app, remote_addr, shutdown).await;
+    };
+
+    let tls_stream =
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.acceptor().accept(stream)).await {
+            Ok(Ok(tls_stream)) => tls_stream,
+            Ok(Err(e)) => {
+                tracing::debug!("TLS handshake with {remote_addr} failed: {e}");
+                return;
+            }
+            Err(_) => {
+                tracing::debug!("TLS handshake with {remote_addr} timed out");
+                return;
+            }
+        };
+
+    serve_connection(TokioIo::new(tls_stream), app, remote_addr, shutdown).await;
+}
+
+async fn serve_connection<I>(io: I, app: Router, remote_addr: Addr, shutdown: CancellationToken)
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let info = ConnectInfo(remote_addr.clone());
+    let service = service_fn(move |mut req: hyper::Request<Incoming>| {
+        req.extensions_mut().insert(info.clone());
+        app.clone().oneshot(req.map(Body::new))
+    });
+
+    // Upgrades are needed for WebSockets
+    let builder = Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(io, service);
+    tokio::pin!(conn);
+
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = shutdown.cancelled() => {
+            // Finish the requests in progress and close the connection
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+
+    if let Err(e) = result {
+        tracing::debug!("connection with {remote_addr} failed: {e}");
+    }
+}
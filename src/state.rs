@@ -1,32 +1,84 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use bytesize::ByteSize;
-use tokio::sync::broadcast;
+use chrono::{DateTime, Utc};
+use futures::StreamExt as _;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::{cli::Cli, req::PubReq, script::Script};
+use crate::{
+    admin::AdminToken,
+    cli::{Cli, LagPolicy},
+    history::History,
+    metrics::Metrics,
+    msg::Msg,
+    req::{PubReq, SubReq},
+    sandbox::Sandbox,
+    script::{Script, Timeouts},
+};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub broadcast: broadcast::Sender<PubReq>,
+    pub topics: Topics,
+    pub subscribers: Subscribers,
+    pub history: History,
+    /// Held while a message is pushed to the history and sent to the
+    /// subscribers, so that both get the messages in the same order.
+    pub publish_lock: Arc<tokio::sync::Mutex<()>>,
+    pub metrics: Metrics,
     pub script: Script,
     pub keep_alive: Duration,
     pub keep_alive_text: String,
     pub timeout: Duration,
     pub timeout_retry: Duration,
+    pub poll_timeout: Duration,
+    pub shutdown: CancellationToken,
+    pub shutdown_retry: Duration,
+    pub shutdown_retry_jitter: Duration,
+    /// Tracks the subscribers and their `unsubscribe` calls so that they
+    /// can be drained when the server shuts down.
+    pub tasks: TaskTracker,
+    pub lag_policy: LagPolicy,
     pub max_body_size: ByteSize,
     pub pub_path: String,
+    pub metrics_path: String,
+    pub admin_path: String,
+    pub admin_token: Option<AdminToken>,
     pub sub_path: String,
     pub serve_static_dir: Option<PathBuf>,
     pub serve_static_path: String,
 }
 
 impl AppState {
-    pub async fn from_cli(cli: &Cli) -> anyhow::Result<Self> {
-        let script = if cli.unsafe_script {
-            Script::unsafe_new()
+    pub async fn from_cli(cli: &Cli) -> anyhow::Result<Arc<Self>> {
+        let metrics = Metrics::new();
+
+        let mut script = if cli.unsafe_script {
+            Script::unsafe_new(metrics.clone(), cli.script_workers.get())
         } else {
-            Script::new()
-        };
+            Script::new(metrics.clone(), cli.script_workers.get())
+        }
+        .with_timeouts(Timeouts::from_cli(cli));
+
+        if let Some(limit) = cli.script_memory_limit {
+            script = script.with_memory_limit(limit.as_u64() as usize)?;
+        }
+
+        if cli.script_sandbox {
+            script = script.with_sandbox(&Sandbox::from_cli(cli)?)?;
+        }
 
         if let Some(path) = &cli.script {
             script.load_path(path).await?;
@@ -36,18 +88,412 @@ impl AppState {
 
         let (broadcast, _) = broadcast::channel(cli.capacity);
 
-        Ok(Self {
+        let history = match &cli.history_db {
+            Some(path) => History::sqlite(path, cli.history_size, cli.history_ttl).await?,
+            None => History::memory(cli.history_size, cli.history_ttl),
+        };
+
+        let state = Self {
             broadcast,
+            topics: Topics::new(cli.capacity),
+            subscribers: Subscribers::new(cli.capacity),
+            history,
+            publish_lock: Arc::new(tokio::sync::Mutex::new(())),
+            metrics,
             script,
             keep_alive: cli.keep_alive,
             keep_alive_text: cli.keep_alive_text.clone(),
             timeout: cli.timeout,
             timeout_retry: cli.timeout_retry,
+            poll_timeout: cli.poll_timeout,
+            shutdown: CancellationToken::new(),
+            shutdown_retry: cli.shutdown_retry,
+            shutdown_retry_jitter: cli.shutdown_retry_jitter,
+            tasks: TaskTracker::new(),
+            lag_policy: cli.lag_policy,
             max_body_size: cli.max_body_size,
             pub_path: cli.pub_path.clone(),
+            metrics_path: cli.metrics_path.clone(),
+            admin_path: cli.admin_path.clone(),
+            admin_token: cli.admin_token.clone(),
             sub_path: cli.sub_path.clone(),
             serve_static_dir: cli.serve_static_dir.clone(),
             serve_static_path: cli.serve_static_path.clone(),
-        })
+        };
+
+        let state = Arc::new(state);
+        state.script.attach(&state);
+
+        Ok(state)
+    }
+
+    /// Sends a publish request to the subscribers of its topics, or to every
+    /// subscriber without topics if it has none.
+    ///
+    /// Returns the number of subscriptions the message was queued for and the
+    /// number of messages queued in the busiest of its channels.
+    pub async fn broadcast(&self, pub_req: PubReq) -> (usize, usize) {
+        let _order = self.publish_lock.lock().await;
+
+        let pub_req = match self.history.push(&pub_req).await {
+            Ok(position) => pub_req.with_position(position),
+            Err(e) => {
+                tracing::error!("{e}");
+                pub_req
+            }
+        };
+
+        if pub_req.topics().is_empty() {
+            let subs = self.broadcast.send(pub_req).unwrap_or(0);
+            return (subs, self.broadcast.len());
+        }
+
+        let mut subs = 0;
+        let mut queued = 0;
+
+        for topic in pub_req.topics() {
+            subs += self.topics.send(topic, pub_req.clone());
+            queued = queued.max(self.topics.len(topic));
+        }
+
+        (subs, queued)
+    }
+}
+
+/// A registry of per-topic broadcast channels.
+///
+/// Each topic gets its own channel, created when the first subscriber
+/// for it arrives, so that subscribers only wake for messages published
+/// to the topics they asked for.  Messages published without a topic go
+/// through `AppState::broadcast` instead.
+#[derive(Debug, Clone)]
+pub struct Topics {
+    capacity: usize,
+    senders: Arc<Mutex<HashMap<String, broadcast::Sender<PubReq>>>>,
+}
+
+impl Topics {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            senders: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Subscribes to a topic, creating its channel if it does not exist yet.
+    pub fn subscribe(&self, topic: &str) -> TopicStream {
+        let mut senders = self.senders.lock().expect("lock topics");
+
+        let receiver = match senders.get(topic) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = broadcast::channel(self.capacity);
+                senders.insert(topic.to_string(), sender);
+                receiver
+            }
+        };
+
+        TopicStream {
+            stream: Some(BroadcastStream::new(receiver)),
+            topics: self.clone(),
+            topic: topic.to_string(),
+        }
+    }
+
+    /// Sends a message to a topic.
+    ///
+    /// Returns the number of receivers the message was queued for.  Topics
+    /// without any subscribers are dropped from the registry.
+    pub fn send(&self, topic: &str, pub_req: PubReq) -> usize {
+        let mut senders = self.senders.lock().expect("lock topics");
+
+        let Some(sender) = senders.get(topic) else {
+            return 0;
+        };
+
+        match sender.send(pub_req) {
+            Ok(subs) => subs,
+            Err(_) => {
+                senders.remove(topic);
+                0
+            }
+        }
+    }
+
+    /// The number of messages each channel can queue.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of messages queued in each topic, as `len` does.
+    pub fn lens(&self) -> HashMap<String, usize> {
+        self.senders
+            .lock()
+            .expect("lock topics")
+            .iter()
+            .map(|(topic, sender)| (topic.clone(), sender.len()))
+            .collect()
+    }
+
+    /// The number of messages queued in a topic that have not been
+    /// received by all of its subscribers.
+    pub fn len(&self, topic: &str) -> usize {
+        self.senders
+            .lock()
+            .expect("lock topics")
+            .get(topic)
+            .map(|sender| sender.len())
+            .unwrap_or(0)
+    }
+
+    /// Drops the channel of a topic if it no longer has any subscribers.
+    fn prune(&self, topic: &str) {
+        let mut senders = self.senders.lock().expect("lock topics");
+
+        if senders
+            .get(topic)
+            .is_some_and(|sender| sender.receiver_count() == 0)
+        {
+            senders.remove(topic);
+        }
+    }
+}
+
+/// The messages of a topic for one subscriber.  The channel of the topic is
+/// dropped with its last subscriber, once the receiver is gone.
+#[derive(Debug)]
+pub struct TopicStream {
+    stream: Option<BroadcastStream<PubReq>>,
+    topics: Topics,
+    topic: String,
+}
+
+impl futures::Stream for TopicStream {
+    type Item = Result<PubReq, BroadcastStreamRecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.stream.as_mut() {
+            Some(stream) => stream.poll_next_unpin(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Drop for TopicStream {
+    fn drop(&mut self) {
+        self.stream.take();
+        self.topics.prune(&self.topic);
+    }
+}
+
+/// A message from the script to a single subscriber stream.
+#[derive(Debug, Clone)]
+pub enum Control {
+    /// Sends a message to the subscriber, without the `message` function.
+    Send(Msg),
+    /// Disconnects the subscriber, asking it to reconnect after a delay.
+    Disconnect(Duration),
+}
+
+/// A registry of the live subscriber streams by the `sub.id` of their
+/// subscribe requests, so that the script can address a single subscriber
+/// instead of broadcasting to all of them, and list the subscribers with
+/// what they were sent.
+///
+/// Each stream gets its own control channel, which is dropped from the
+/// registry with its `Registration`.
+#[derive(Debug, Clone)]
+pub struct Subscribers {
+    capacity: usize,
+    next: Arc<AtomicU64>,
+    streams: Arc<Mutex<BTreeMap<u64, Stream>>>,
+}
+
+#[derive(Debug)]
+struct Stream {
+    sub_req: SubReq,
+    meta: serde_json::Value,
+    connected_at: DateTime<Utc>,
+    stats: Arc<Stats>,
+    control: mpsc::Sender<Control>,
+}
+
+impl Subscribers {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            next: Arc::new(AtomicU64::new(0)),
+            streams: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Registers the control channel of a subscriber stream.
+    ///
+    /// The fields set by the script are copied as JSON, so that listing the
+    /// subscribers does not wait for the worker that holds their table.
+    pub fn register(&self, sub_req: &SubReq) -> Registration {
+        let key = self.next.fetch_add(1, Ordering::Relaxed);
+        let (control, receiver) = mpsc::channel(self.capacity);
+        let stats = Arc::new(Stats::default());
+
+        self.streams.lock().expect("lock subscribers").insert(
+            key,
+            Stream {
+                sub_req: sub_req.clone(),
+                meta: sub_req.meta_json(),
+                connected_at: Utc::now(),
+                stats: stats.clone(),
+                control,
+            },
+        );
+
+        Registration {
+            subscribers: self.clone(),
+            key,
+            stats,
+            receiver,
+        }
+    }
+
+    /// Sends a control message to the streams of a subscriber id, which
+    /// can be several when the script gives the same id to many subscribers.
+    ///
+    /// Returns the number of streams the message was queued for.  Streams
+    /// that have fallen too far behind to take another message are skipped.
+    pub fn control(&self, id: &str, control: Control) -> usize {
+        self.control_where(|sub_req| sub_req.id() == id, control)
+    }
+
+    /// Sends a control message to the streams whose subscribe requests
+    /// match, as `control` does for an id.
+    pub fn control_where(&self, matches: impl Fn(&SubReq) -> bool, control: Control) -> usize {
+        self.streams
+            .lock()
+            .expect("lock subscribers")
+            .values()
+            .filter(|stream| matches(&stream.sub_req))
+            .filter(|stream| match stream.control.try_send(control.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    let id = stream.sub_req.id();
+                    tracing::warn!("subscriber {id:?} did not take a control message: {e}");
+                    false
+                }
+            })
+            .count()
+    }
+
+    /// The live subscriber streams, in the order they connected.
+    pub fn list(&self) -> Vec<Subscriber> {
+        self.streams
+            .lock()
+            .expect("lock subscribers")
+            .values()
+            .map(|stream| Subscriber {
+                sub_req: stream.sub_req.clone(),
+                meta: stream.meta.clone(),
+                connected_at: stream.connected_at,
+                messages: stream.stats.messages.load(Ordering::Relaxed),
+                bytes: stream.stats.bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// The control channel of a subscriber stream, which is removed from the
+/// registry when dropped.
+#[derive(Debug)]
+pub struct Registration {
+    subscribers: Subscribers,
+    key: u64,
+    stats: Arc<Stats>,
+    receiver: mpsc::Receiver<Control>,
+}
+
+impl Registration {
+    /// Receives the next control message for the stream.
+    pub async fn recv(&mut self) -> Option<Control> {
+        self.receiver.recv().await
+    }
+
+    /// The counters of what the transport sent to the subscriber.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.subscribers
+            .streams
+            .lock()
+            .expect("lock subscribers")
+            .remove(&self.key);
+    }
+}
+
+/// The number of messages and bytes sent to a subscriber, including the
+/// comments of the server but not the keep-alives.
+#[derive(Debug, Default)]
+pub struct Stats {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Stats {
+    /// Counts a message sent to the subscriber.
+    pub fn sent(&self, bytes: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A live subscriber stream, as listed by `Subscribers::list`.
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub sub_req: SubReq,
+    /// The fields set by the script when the subscriber registered.
+    pub meta: serde_json::Value,
+    pub connected_at: DateTime<Utc>,
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl mlua::IntoLua for Subscriber {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        // A copy of the `sub` table, which must not be changed for the
+        // functions called with it
+        let tbl = lua.create_table()?;
+
+        if let mlua::Value::Table(sub) = self.sub_req.into_lua(lua)? {
+            for pair in sub.pairs::<mlua::Value, mlua::Value>() {
+                let (key, val) = pair?;
+                tbl.set(key, val)?;
+            }
+        }
+
+        tbl.set("connected_at", self.connected_at.timestamp_millis())?;
+        tbl.set("messages", self.messages)?;
+        tbl.set("bytes", self.bytes)?;
+
+        Ok(mlua::Value::Table(tbl))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_channel_is_dropped_with_its_last_stream() {
+        let topics = Topics::new(4);
+        let first = topics.subscribe("news");
+        let second = topics.subscribe("news");
+
+        drop(first);
+        assert!(topics.lens().contains_key("news"));
+
+        drop(second);
+        assert!(!topics.lens().contains_key("news"));
     }
 }
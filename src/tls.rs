@@ -0,0 +1,159 @@
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context as _;
+use rustls::{
+    InconsistentKeys, ServerConfig,
+    crypto::CryptoProvider,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// TLS termination with a certificate and private key loaded from PEM files.
+///
+/// The files are watched for changes and reloaded without a restart.  New
+/// connections use the new certificate while existing connections are not
+/// affected.
+#[derive(Debug, Clone)]
+pub struct Tls {
+    config: Arc<ServerConfig>,
+}
+
+impl Tls {
+    /// Loads the certificate and key, and starts watching them for changes.
+    pub fn load<P: AsRef<Path>>(cert_path: P, key_path: P) -> anyhow::Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let resolver = Arc::new(CertResolver {
+            cert_path: cert_path.as_ref().to_path_buf(),
+            key_path: key_path.as_ref().to_path_buf(),
+            provider: provider.clone(),
+            key: RwLock::new(Arc::new(load_certified_key(
+                &provider,
+                cert_path.as_ref(),
+                key_path.as_ref(),
+            )?)),
+        });
+
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+
+        // Prefer HTTP/2 so that browsers are not limited to a few concurrent
+        // EventSource connections per origin.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        tokio::spawn(resolver.watch());
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.clone())
+    }
+}
+
+/// Resolves the currently loaded certificate for every connection.
+#[derive(Debug)]
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Reloads the certificate and key whenever either file is modified.
+    ///
+    /// If the files cannot be loaded (for instance while they are being
+    /// replaced), then the error is logged and the current certificate is
+    /// kept.
+    async fn watch(self: Arc<Self>) {
+        let mut modified = self.modified();
+
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+
+            let current = self.modified();
+
+            if current == modified {
+                continue;
+            }
+
+            match load_certified_key(&self.provider, &self.cert_path, &self.key_path) {
+                Ok(key) => {
+                    *self.key.write().expect("lock tls key") = Arc::new(key);
+                    modified = current;
+                    tracing::info!("Reloaded TLS certificate {}", self.cert_path.display());
+                }
+                Err(e) => tracing::error!("{e:#}"),
+            }
+        }
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().expect("lock tls key").clone())
+    }
+}
+
+fn load_certified_key(
+    provider: &CryptoProvider,
+    cert_path: &Path,
+    key_path: &Path,
+) -> anyhow::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        fs::File::open(cert_path)
+            .with_context(|| format!("open TLS certificate {}", cert_path.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("read TLS certificate {}", cert_path.display()))?;
+
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", cert_path.display());
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        fs::File::open(key_path)
+            .with_context(|| format!("open TLS private key {}", key_path.display()))?,
+    ))
+    .with_context(|| format!("read TLS private key {}", key_path.display()))?
+    .with_context(|| format!("no private key found in {}", key_path.display()))?;
+
+    let key = provider
+        .key_provider
+        .load_private_key(key)
+        .with_context(|| format!("load TLS private key {}", key_path.display()))?;
+
+    let key = CertifiedKey::new(certs, key);
+
+    // A key whose public key the provider cannot tell is not rejected, as in
+    // `ServerConfig::with_single_cert`
+    match key.keys_match() {
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => Ok(key),
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "TLS private key {} does not match certificate {}",
+                key_path.display(),
+                cert_path.display()
+            )
+        }),
+    }
+}
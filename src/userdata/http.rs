@@ -1,5 +1,11 @@
+use crate::sandbox::{self, Sandbox};
+
 pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
+/// The maximum number of redirects followed by a sandboxed script, as by
+/// default.
+const MAX_REDIRECTS: usize = 10;
+
 pub struct Http;
 
 impl Http {
@@ -9,18 +15,18 @@ impl Http {
 }
 
 impl Http {
-    pub fn agent() -> Agent {
-        Agent::default()
+    pub fn agent(lua: &mlua::Lua) -> Agent {
+        Agent::new(lua)
     }
 }
 
 impl mlua::UserData for Http {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_function("agent", |_lua, opts: Option<mlua::Table>| {
+        methods.add_function("agent", |lua, opts: Option<mlua::Table>| {
             if let Some(opts) = opts {
-                Agent::new_with_opts(opts)
+                Agent::new_with_opts(lua, opts)
             } else {
-                Ok(Agent::new())
+                Ok(Agent::new(lua))
             }
         });
 
@@ -30,56 +36,56 @@ impl mlua::UserData for Http {
                 let method = reqwest::Method::from_bytes(method.as_bytes())
                     .map_err(|e| Http::error(format!("method is invalid: {e}")))?;
 
-                Http::agent().request(&lua, method, url, opts).await
+                Http::agent(&lua).request(&lua, method, url, opts).await
             },
         );
 
         methods.add_async_function(
             "get",
             |lua, (url, opts): (String, Option<mlua::Table>)| async move {
-                Http::agent().get(&lua, url, opts).await
+                Http::agent(&lua).get(&lua, url, opts).await
             },
         );
 
         methods.add_async_function(
             "head",
             |lua, (url, opts): (String, Option<mlua::Table>)| async move {
-                Http::agent().head(&lua, url, opts).await
+                Http::agent(&lua).head(&lua, url, opts).await
             },
         );
 
         methods.add_async_function(
             "post",
             |lua, (url, opts): (String, Option<mlua::Table>)| async move {
-                Http::agent().post(&lua, url, opts).await
+                Http::agent(&lua).post(&lua, url, opts).await
             },
         );
 
         methods.add_async_function(
             "put",
             |lua, (url, opts): (String, Option<mlua::Table>)| async move {
-                Http::agent().put(&lua, url, opts).await
+                Http::agent(&lua).put(&lua, url, opts).await
             },
         );
 
         methods.add_async_function(
             "patch",
             |lua, (url, opts): (String, Option<mlua::Table>)| async move {
-                Http::agent().patch(&lua, url, opts).await
+                Http::agent(&lua).patch(&lua, url, opts).await
             },
         );
 
         methods.add_async_function(
             "delete",
             |lua, (url, opts): (String, Option<mlua::Table>)| async move {
-                Http::agent().delete(&lua, url, opts).await
+                Http::agent(&lua).delete(&lua, url, opts).await
             },
         );
 
         methods.add_async_function(
             "options",
             |lua, (url, opts): (String, Option<mlua::Table>)| async move {
-                Http::agent().options(&lua, url, opts).await
+                Http::agent(&lua).options(&lua, url, opts).await
             },
         );
     }
@@ -91,13 +97,32 @@ pub struct Agent {
 }
 
 impl Agent {
-    pub fn builder() -> reqwest::ClientBuilder {
-        reqwest::Client::builder()
+    /// A client builder that only follows redirects to the hosts allowed by
+    /// the sandbox of the Lua state, if it has one.
+    pub fn builder(lua: &mlua::Lua) -> reqwest::ClientBuilder {
+        let builder = reqwest::Client::builder();
+
+        match lua.app_data_ref::<Sandbox>() {
+            Some(sandbox) => {
+                let sandbox = sandbox.clone();
+
+                builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                    if attempt.previous().len() >= MAX_REDIRECTS {
+                        attempt.error("too many redirects")
+                    } else if let Err(e) = sandbox.allow_url(attempt.url()) {
+                        attempt.error(e)
+                    } else {
+                        attempt.follow()
+                    }
+                }))
+            }
+            None => builder,
+        }
     }
 
-    pub fn new() -> Self {
+    pub fn new(lua: &mlua::Lua) -> Self {
         Self {
-            client: Self::builder()
+            client: Self::builder(lua)
                 .user_agent(USER_AGENT)
                 .build()
                 .expect("build reqwest http client"),
@@ -105,8 +130,8 @@ impl Agent {
         }
     }
 
-    pub fn new_with_opts(opts: mlua::Table) -> mlua::Result<Self> {
-        let client = Self::builder()
+    pub fn new_with_opts(lua: &mlua::Lua, opts: mlua::Table) -> mlua::Result<Self> {
+        let client = Self::builder(lua)
             .user_agent(USER_AGENT)
             .build()
             .map_err(|e| Http::error(e.to_string()))?;
@@ -214,6 +239,8 @@ impl Agent {
             .as_ref()
             .parse()
             .map_err(|e| Http::error(format!("url is invalid: {e}")))?;
+        sandbox::check(lua, |sandbox| sandbox.allow_url(&url))?;
+
         let mut req = self.client.request(method, url);
 
         let opts = match (self.opts.as_ref(), opts) {
@@ -264,17 +291,15 @@ impl Agent {
             req = req.body(body.as_bytes().to_vec());
         }
 
-        let res = req.send().await.map_err(|e| Http::error(e.to_string()))?;
+        // Include the causes, such as a redirect the sandbox does not allow
+        let res = req
+            .send()
+            .await
+            .map_err(|e| Http::error(format!("{:#}", anyhow::Error::from(e))))?;
         into_lua_res(lua, res).await
     }
 }
 
-impl Default for Agent {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 impl mlua::UserData for Agent {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
         methods.add_async_method(
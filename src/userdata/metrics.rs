@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use crate::metrics::{self, ScriptMetric, ScriptMetricKind};
+
+/// The default buckets of histograms, as in the Prometheus client libraries.
+const DEFAULT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A Lua userdata type that defines metrics exported with the server metrics.
+///
+/// # Example
+///
+/// ```lua
+/// local metrics = require "metrics"
+///
+/// local failures = metrics.counter("auth_failures", "Authentication failures", {"reason"})
+/// failures:inc(1, {reason = "expired"})
+///
+/// local occupancy = metrics.gauge("occupancy", "Subscribers per channel", {"channel"})
+/// occupancy:set(10, {channel = "news"})
+///
+/// local latency = metrics.histogram("auth_seconds", "Authentication latency")
+/// latency:observe(0.2)
+/// ```
+#[derive(Clone)]
+pub struct Metrics(pub metrics::Metrics);
+
+type LabelNames = Option<Vec<String>>;
+
+impl Metrics {
+    /// Defines a metric with the server metrics of a Lua state, which are
+    /// kept in its app data.
+    fn define(
+        lua: &mlua::Lua,
+        name: String,
+        help: Option<String>,
+        labels: LabelNames,
+        kind: ScriptMetricKind,
+    ) -> mlua::Result<ScriptMetric> {
+        let Some(metrics) = lua.app_data_ref::<Self>() else {
+            return Err(mlua::Error::runtime(
+                "the metrics package is not registered",
+            ));
+        };
+
+        metrics
+            .0
+            .script_metric(
+                &name,
+                help.as_deref().unwrap_or_default(),
+                labels.unwrap_or_default(),
+                kind,
+            )
+            .map_err(mlua::Error::external)
+    }
+}
+
+impl mlua::UserData for Metrics {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function(
+            "counter",
+            |lua, (name, help, labels): (String, Option<String>, LabelNames)| {
+                Self::define(lua, name, help, labels, ScriptMetricKind::Counter).map(Counter)
+            },
+        );
+        methods.add_function(
+            "gauge",
+            |lua, (name, help, labels): (String, Option<String>, LabelNames)| {
+                Self::define(lua, name, help, labels, ScriptMetricKind::Gauge).map(Gauge)
+            },
+        );
+        methods.add_function(
+            "histogram",
+            |lua,
+             (name, help, labels, buckets): (
+                String,
+                Option<String>,
+                LabelNames,
+                Option<Vec<f64>>,
+            )| {
+                let buckets = buckets.unwrap_or_else(|| DEFAULT_BUCKETS.to_vec());
+                Self::define(
+                    lua,
+                    name,
+                    help,
+                    labels,
+                    ScriptMetricKind::Histogram(buckets),
+                )
+                .map(Histogram)
+            },
+        );
+    }
+}
+
+type Labels = Option<HashMap<String, String>>;
+
+/// A counter defined by the script.
+struct Counter(ScriptMetric);
+
+impl mlua::UserData for Counter {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method(
+            "inc",
+            |_lua, this, (value, labels): (Option<f64>, Labels)| {
+                this.0
+                    .inc(value.unwrap_or(1.0), &labels.unwrap_or_default())
+                    .map_err(mlua::Error::external)
+            },
+        );
+    }
+}
+
+/// A gauge defined by the script.
+struct Gauge(ScriptMetric);
+
+impl mlua::UserData for Gauge {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method(
+            "inc",
+            |_lua, this, (value, labels): (Option<f64>, Labels)| {
+                this.0
+                    .inc(value.unwrap_or(1.0), &labels.unwrap_or_default())
+                    .map_err(mlua::Error::external)
+            },
+        );
+        methods.add_method(
+            "dec",
+            |_lua, this, (value, labels): (Option<f64>, Labels)| {
+                this.0
+                    .dec(value.unwrap_or(1.0), &labels.unwrap_or_default())
+                    .map_err(mlua::Error::external)
+            },
+        );
+        methods.add_method("set", |_lua, this, (value, labels): (f64, Labels)| {
+            this.0
+                .set(value, &labels.unwrap_or_default())
+                .map_err(mlua::Error::external)
+        });
+    }
+}
+
+/// A histogram defined by the script.
+struct Histogram(ScriptMetric);
+
+impl mlua::UserData for Histogram {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("observe", |_lua, this, (value, labels): (f64, Labels)| {
+            this.0
+                .observe(value, &labels.unwrap_or_default())
+                .map_err(mlua::Error::external)
+        });
+    }
+}
@@ -3,9 +3,12 @@ pub mod fernet;
 pub mod http;
 pub mod json;
 pub mod log;
+pub mod metrics;
 pub mod mutex;
+pub mod server;
 pub mod sleep;
 pub mod sqlite;
+pub mod store;
 pub mod template;
 pub mod url;
 pub mod uuid;
@@ -15,9 +18,12 @@ pub use fernet::Fernet;
 pub use http::Http;
 pub use json::Json;
 pub use log::Log;
+pub use metrics::Metrics;
 pub use mutex::Mutex;
+pub use server::Server;
 pub use sleep::Sleep;
 pub use sqlite::Sqlite;
+pub use store::Store;
 pub use template::Template;
 pub use url::Url;
 pub use uuid::Uuid;
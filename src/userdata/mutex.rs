@@ -1,3 +1,5 @@
+use crate::script;
+
 pub struct Mutex;
 
 impl mlua::UserData for Mutex {
@@ -18,9 +20,9 @@ impl mlua::UserData for InnerMutex {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
         methods.add_async_meta_method(
             mlua::MetaMethod::Call,
-            |_lua, this, func: mlua::Function| async move {
+            |lua, this, func: mlua::Function| async move {
                 let _guard = this.inner.lock().await;
-                func.call_async::<mlua::MultiValue>(()).await
+                script::call_async::<mlua::MultiValue>(&lua, func, ()).await
             },
         );
     }
@@ -0,0 +1,182 @@
+use std::{
+    fmt,
+    sync::{Arc, OnceLock, Weak},
+    time::Duration,
+};
+
+use mlua::FromLua as _;
+
+use crate::{
+    msg::Msg,
+    req::{PubReq, Req},
+    state::{AppState, Control, Subscriber},
+};
+
+/// A Lua userdata type that gives the script access to the server.
+///
+/// The server state is only attached once the script is loaded, so the
+/// functions raise an error when called at the top level of the script.
+/// Since the state includes the script, the package only keeps a weak
+/// reference to it, which the server holds until it exits.
+///
+/// # Example
+///
+/// ```lua
+/// local server = require "server"
+///
+/// -- Publish a message to the subscribers without topics
+/// server.publish { event = "tick", data = "1" }
+/// -- 3
+///
+/// -- Publish a message to topics, through the `publish` function first
+/// server.publish({ data = "hello" }, { topics = { "news" }, hook = true })
+/// -- 1, or nil if rejected by the `publish` function
+///
+/// -- Send a message to the subscriber with the given `sub.id` only
+/// server.send(sub.id, { event = "notice", data = "hello" })
+/// -- 1
+///
+/// -- List the subscribers with what they were sent
+/// for _, sub in ipairs(server.subscribers()) do
+///     print(sub.id, sub.req.addr.ip, sub.messages, sub.bytes)
+/// end
+///
+/// -- Disconnect the subscriber, which may reconnect after 10 seconds
+/// server.disconnect(sub.id, 10000)
+/// -- 1
+/// ```
+#[derive(Clone, Default)]
+pub struct Server(Arc<OnceLock<Weak<AppState>>>);
+
+// The state includes the script and so this package
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("attached", &self.0.get().is_some())
+            .finish()
+    }
+}
+
+impl Server {
+    /// Attaches the state of the server, which can only be done once.
+    pub fn attach(&self, state: &Arc<AppState>) {
+        if self.0.set(Arc::downgrade(state)).is_err() {
+            tracing::warn!("server state is already attached to the script");
+        }
+    }
+
+    /// The package of a Lua state, which is kept in its app data.
+    fn of(lua: &mlua::Lua) -> mlua::Result<Self> {
+        lua.app_data_ref::<Self>()
+            .map(|server| server.clone())
+            .ok_or_else(|| mlua::Error::runtime("the server package is not registered"))
+    }
+
+    fn state(&self) -> mlua::Result<Arc<AppState>> {
+        let state = self
+            .0
+            .get()
+            .ok_or_else(|| mlua::Error::runtime("the server is not started yet"))?;
+
+        state
+            .upgrade()
+            .ok_or_else(|| mlua::Error::runtime("the server has exited"))
+    }
+
+    /// Publishes a message as if it was posted to the publish path, but
+    /// only through the `publish` function if `hook` is true.
+    ///
+    /// Returns the number of subscriptions the message was queued for, or
+    /// `None` if the `publish` function rejected it.  A message published
+    /// from a function called on every worker, such as `tick`, is published
+    /// once per worker.
+    async fn publish(
+        &self,
+        lua: &mlua::Lua,
+        msg: Msg,
+        opts: Option<mlua::Table>,
+    ) -> mlua::Result<Option<usize>> {
+        let state = self.state()?;
+
+        let (topics, hook) = match opts {
+            Some(opts) => (
+                match opts.get::<mlua::Value>("topics")? {
+                    mlua::Value::Nil => Vec::new(),
+                    mlua::Value::String(topic) => vec![topic.to_str()?.to_string()],
+                    topics => Vec::<String>::from_lua(topics, lua)?,
+                },
+                opts.get::<Option<bool>>("hook")?.unwrap_or_default(),
+            ),
+            None => (Vec::new(), false),
+        };
+
+        let pub_req = PubReq::new(Req::script(&state.pub_path), msg, topics);
+
+        let pub_req = if hook {
+            match state.script.publish(pub_req).await {
+                Ok(Some(pub_req)) => pub_req,
+                Ok(None) => {
+                    state.metrics.publish("rejected");
+                    return Ok(None);
+                }
+                Err(e) => {
+                    state.metrics.publish("error");
+                    return Err(mlua::Error::external(e));
+                }
+            }
+        } else {
+            pub_req
+        };
+
+        state.metrics.publish("accepted");
+        let (subs, _) = state.broadcast(pub_req).await;
+
+        Ok(Some(subs))
+    }
+
+    /// Sends a message to the subscriber streams with the given id.
+    ///
+    /// Returns the number of streams the message was queued for.
+    fn send(&self, id: &str, msg: Msg) -> mlua::Result<usize> {
+        let state = self.state()?;
+
+        Ok(state.subscribers.control(id, Control::Send(msg)))
+    }
+
+    /// The live SSE and WebSocket subscribers.
+    fn subscribers(&self) -> mlua::Result<Vec<Subscriber>> {
+        Ok(self.state()?.subscribers.list())
+    }
+
+    /// Disconnects the subscriber streams with the given id, asking them
+    /// to reconnect after `retry_ms`, or `--timeout-retry` by default.
+    ///
+    /// Returns the number of streams that were asked to disconnect.
+    fn disconnect(&self, id: &str, retry_ms: Option<u64>) -> mlua::Result<usize> {
+        let state = self.state()?;
+        let retry = retry_ms
+            .map(Duration::from_millis)
+            .unwrap_or(state.timeout_retry);
+
+        Ok(state.subscribers.control(id, Control::Disconnect(retry)))
+    }
+}
+
+impl mlua::UserData for Server {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_function(
+            "publish",
+            |lua, (msg, opts): (Msg, Option<mlua::Table>)| async move {
+                Self::of(&lua)?.publish(&lua, msg, opts).await
+            },
+        );
+        methods.add_function("send", |lua, (id, msg): (String, Msg)| {
+            Self::of(lua)?.send(&id, msg)
+        });
+        methods.add_function("subscribers", |lua, ()| Self::of(lua)?.subscribers());
+        methods.add_function(
+            "disconnect",
+            |lua, (id, retry_ms): (String, Option<u64>)| Self::of(lua)?.disconnect(&id, retry_ms),
+        );
+    }
+}
@@ -2,6 +2,8 @@
 use mlua::LuaSerdeExt as _;
 use tokio_sqlite as sqlite;
 
+use crate::sandbox;
+
 /// The SQLite database interface.
 pub struct Sqlite;
 
@@ -28,7 +30,18 @@ impl mlua::UserData for Sqlite {
     /// Adds Lua methods for the `Sqlite` struct.
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
         /// Opens a SQLite database from Lua.
-        methods.add_async_function("open", |_lua, path: String| async move {
+        methods.add_async_function("open", |lua, path: String| async move {
+            if path != ":memory:" {
+                sandbox::check(&lua, |sandbox| {
+                    // URI filenames are paths with options
+                    if path.starts_with("file:") {
+                        return Err("SQLite URI filenames are not allowed by the script sandbox"
+                            .to_string());
+                    }
+                    sandbox.allow_path(&path)
+                })?;
+            }
+
             Self::open(&path).await.map_err(mlua::Error::external)
         });
     }
@@ -86,7 +99,7 @@ impl Connection {
     /// # Returns
     ///
     /// A `Result` containing the rows or a `sqlite::Error`.
-    pub async fn query<S, A>(&mut self, stmt: S, args: A) -> Result<sqlite::Rows, sqlite::Error>
+    pub async fn query<S, A>(&mut self, stmt: S, args: A) -> Result<sqlite::Rows<'_>, sqlite::Error>
     where
         S: Into<String>,
         A: Into<Vec<sqlite::Value>>,
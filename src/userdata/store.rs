@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use mlua::LuaSerdeExt as _;
+
+/// A Lua userdata type that provides a key/value store shared by the script
+/// workers.
+///
+/// Values are copied in and out of the store, so they can be anything that
+/// can be encoded as JSON.
+///
+/// # Example
+///
+/// ```lua
+/// local store = require "store"
+///
+/// store.set("config", { channels = { "news", "sports" } })
+/// store.get("config").channels
+/// -- { "news", "sports" }
+///
+/// -- Atomically increment (or decrement) a number
+/// store.incr("subscribers")
+/// -- 1
+/// store.incr("subscribers", -1)
+/// -- 0
+///
+/// store.delete("config")
+/// store.get("config")
+/// -- nil
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Store(Arc<Mutex<HashMap<String, serde_json::Value>>>);
+
+impl Store {
+    /// The store of a Lua state, which is kept in its app data.
+    fn of(lua: &mlua::Lua) -> mlua::Result<Self> {
+        lua.app_data_ref::<Self>()
+            .map(|store| store.clone())
+            .ok_or_else(|| mlua::Error::runtime("the store package is not registered"))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, serde_json::Value>> {
+        self.0.lock().expect("lock store")
+    }
+}
+
+impl mlua::UserData for Store {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function("get", |lua, key: String| {
+            match Self::of(lua)?.lock().get(&key) {
+                Some(value) => lua.to_value(value),
+                None => Ok(mlua::Value::Nil),
+            }
+        });
+        methods.add_function("set", |lua, (key, value): (String, mlua::Value)| {
+            let store = Self::of(lua)?;
+            if value.is_nil() {
+                store.lock().remove(&key);
+            } else {
+                let value = lua.from_value::<serde_json::Value>(value)?;
+                store.lock().insert(key, value);
+            }
+            Ok(())
+        });
+        methods.add_function("delete", |lua, key: String| {
+            Self::of(lua)?.lock().remove(&key);
+            Ok(())
+        });
+        methods.add_function("incr", |lua, (key, by): (String, Option<f64>)| {
+            let store = Self::of(lua)?;
+            let mut store = store.lock();
+            let value = match store.get(&key) {
+                Some(value) => value.as_f64().ok_or_else(|| {
+                    mlua::Error::runtime(format!("value of {key:?} is not a number"))
+                })?,
+                None => 0.0,
+            } + by.unwrap_or(1.0);
+
+            let number = if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+                serde_json::Value::from(value as i64)
+            } else {
+                serde_json::Number::from_f64(value)
+                    .map(serde_json::Value::Number)
+                    .ok_or_else(|| mlua::Error::runtime("value is not a finite number"))?
+            };
+
+            let result = lua.to_value(&number);
+            store.insert(key, number);
+            result
+        });
+    }
+}
@@ -1,14 +1,16 @@
 use std::sync::LazyLock;
 
+use crate::sandbox;
+
 static DEFAULT_ENV: LazyLock<Env> = LazyLock::new(Env::default);
 
 pub struct Template;
 
 impl mlua::UserData for Template {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_function("library", |_lua, opts: Option<mlua::Table>| {
+        methods.add_function("library", |lua, opts: Option<mlua::Table>| {
             if let Some(opts) = opts {
-                Env::new_with_opts(opts)
+                Env::new_with_opts(lua, opts)
             } else {
                 Ok(Env::default())
             }
@@ -46,12 +48,13 @@ impl Env {
         Self { env }
     }
 
-    pub fn new_with_opts(opts: mlua::Table) -> mlua::Result<Self> {
+    pub fn new_with_opts(lua: &mlua::Lua, opts: mlua::Table) -> mlua::Result<Self> {
         let mut env = minijinja::Environment::new();
         env.set_auto_escape_callback(|_| minijinja::AutoEscape::Html);
 
         // Load templates from a directory path
         if let Ok(dir) = opts.get::<std::path::PathBuf>("directory") {
+            sandbox::check(lua, |sandbox| sandbox.allow_path(&dir))?;
             env.set_loader(minijinja::path_loader(dir));
         }
 
@@ -1,40 +1,55 @@
 use std::{
+    collections::HashSet,
     convert::Infallible,
-    net::SocketAddr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use axum::{
     Json, Router, body, debug_handler,
-    extract::{ConnectInfo, State},
-    http::StatusCode,
+    extract::{
+        ConnectInfo, State,
+        ws::{self, WebSocketUpgrade},
+    },
+    http::{StatusCode, header},
     response::{
-        IntoResponse, Sse,
+        IntoResponse, Response, Sse,
         sse::{Event, KeepAlive},
     },
     routing::{get, post},
 };
 use axum_extra::{TypedHeader, extract::Query, headers::ContentType};
-use futures::stream::{self, Stream, StreamExt};
+use clap::ValueEnum as _;
+use futures::{
+    FutureExt as _, future,
+    stream::{self, BoxStream, Stream, StreamExt},
+};
 use mime::Mime;
 
 use serde_json::json;
 
-use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
 use tower_http::services::ServeDir;
 
 use crate::{
+    admin,
+    cli::LagPolicy,
     error::AppError,
+    history::Position,
     msg::Msg,
-    req::{PubReq, Req, SubReq, SubReqGuard},
-    state::AppState,
+    req::{Addr, PubReq, Req, SubReq, SubReqGuard},
+    state::{AppState, Control, Registration},
 };
 
-/// Builds the axum router for the application.
+/// Builds the axum router for the application with every route.
 pub fn router(state: &AppState) -> Router<AppState> {
-    let mut router = Router::new()
-        .route(&state.pub_path, post(publish))
-        .route(&state.sub_path, get(subscribe));
+    public_router(state).merge(internal_router(state))
+}
+
+/// Builds the router for the public listener when the internal routes are
+/// served on a separate listener: subscribing and static files.
+pub fn public_router(state: &AppState) -> Router<AppState> {
+    let mut router = Router::new().route(&state.sub_path, get(subscribe));
 
     // Serve static files from the specified directory.
     if let Some(serve_static_dir) = &state.serve_static_dir {
@@ -44,19 +59,87 @@ pub fn router(state: &AppState) -> Router<AppState> {
     router
 }
 
+/// Builds the router for the internal listener: publishing, metrics and
+/// the admin API.
+pub fn internal_router(state: &AppState) -> Router<AppState> {
+    let mut router = Router::new()
+        .route(&state.pub_path, post(publish))
+        .route(&state.metrics_path, get(metrics));
+
+    // The admin API is only served when it is protected by a token
+    if let Some(admin_token) = &state.admin_token {
+        router = router.nest(&state.admin_path, admin::router(admin_token.clone()));
+    }
+
+    router
+}
+
+/// A message decoded from a publish request body, with the topics given
+/// alongside its fields.
+struct PubMsg {
+    msg: Msg,
+    topics: Vec<String>,
+}
+
+/// The messages decoded from a publish request body.
+enum PubBody {
+    /// A single message.
+    One(PubMsg),
+    /// A batch of messages.  Messages that cannot be decoded are rejected
+    /// individually.
+    Batch(Vec<Result<PubMsg, String>>),
+}
+
 /// Utility function to decode raw body based on content type.
 ///
 /// Supported content types:
-///   - application/json
+///   - application/json (a single message object or an array of them)
+///   - application/x-ndjson (one message object per line)
 ///   - application/x-www-form-urlencoded
-fn decode_raw_body(mime: &Mime, raw: &body::Bytes) -> Result<Msg, AppError> {
+///   - text/event-stream (one or more messages)
+fn decode_raw_body(mime: &Mime, raw: &body::Bytes) -> Result<PubBody, AppError> {
     Ok(match (mime.type_(), mime.subtype()) {
         (mime::APPLICATION, mime::JSON) => {
-            serde_json::from_slice(raw).map_err(|e| AppError::BadRequest(e.to_string()))?
+            match serde_json::from_slice(raw).map_err(|e| AppError::BadRequest(e.to_string()))? {
+                serde_json::Value::Array(values) => {
+                    PubBody::Batch(values.into_iter().map(decode_json_msg).collect())
+                }
+                value => PubBody::One(decode_json_msg(value).map_err(AppError::BadRequest)?),
+            }
         }
+        (mime::APPLICATION, subtype) if subtype == "x-ndjson" => PubBody::Batch(
+            raw.split(|b| *b == b'\n')
+                .filter(|line| !line.trim_ascii().is_empty())
+                .map(|line| {
+                    serde_json::from_slice(line)
+                        .map_err(|e| e.to_string())
+                        .and_then(decode_json_msg)
+                })
+                .collect(),
+        ),
         (mime::APPLICATION, mime::WWW_FORM_URLENCODED) => {
             // serde_html_form supports repeated keys as arrays
-            serde_html_form::from_bytes(raw).map_err(|e| AppError::BadRequest(e.to_string()))?
+            let msg = serde_html_form::from_bytes(raw)
+                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+            let TopicQuery { topic: topics } = serde_html_form::from_bytes(raw)
+                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+            PubBody::One(PubMsg { msg, topics })
+        }
+        (mime::TEXT, mime::EVENT_STREAM) => {
+            let text = std::str::from_utf8(raw).map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+            PubBody::Batch(
+                Msg::parse_event_stream(text)
+                    .into_iter()
+                    .map(|msg| {
+                        Ok(PubMsg {
+                            msg,
+                            topics: Vec::new(),
+                        })
+                    })
+                    .collect(),
+            )
         }
         _ => {
             return Err(AppError::UnsupportedMediaType(format!(
@@ -66,58 +149,195 @@ fn decode_raw_body(mime: &Mime, raw: &body::Bytes) -> Result<Msg, AppError> {
     })
 }
 
+fn decode_json_msg(value: serde_json::Value) -> Result<PubMsg, String> {
+    let JsonMsg { msg, topic } = serde_json::from_value(value).map_err(|e| e.to_string())?;
+
+    Ok(PubMsg {
+        msg,
+        topics: match topic {
+            Some(JsonTopics::One(topic)) => vec![topic],
+            Some(JsonTopics::Many(topics)) => topics,
+            None => Vec::new(),
+        },
+    })
+}
+
+/// A message object of a JSON body, with the topics to publish it to
+/// alongside its fields.
+#[derive(Debug, serde::Deserialize)]
+#[serde(expecting = "a message object")]
+struct JsonMsg {
+    #[serde(flatten)]
+    msg: Msg,
+    topic: Option<JsonTopics>,
+}
+
+/// The `topic` of a JSON message, given as a single topic or a list of them.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged, expecting = "a topic or a list of topics")]
+enum JsonTopics {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// The topics to publish to, given either in the query string or in the body
+/// alongside the message fields (`?topic=a&topic=b`).
+#[derive(Debug, Default, serde::Deserialize)]
+struct TopicQuery {
+    #[serde(default)]
+    topic: Vec<String>,
+}
+
 #[debug_handler]
 async fn publish(
     State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ConnectInfo(addr): ConnectInfo<Addr>,
     TypedHeader(content_type): TypedHeader<ContentType>,
+    Query(TopicQuery { topic: topics }): Query<TopicQuery>,
     axum_req: axum::extract::Request,
 ) -> Result<impl IntoResponse, AppError> {
     let req = Req::new(addr, &axum_req);
     let raw = body::to_bytes(axum_req.into_body(), state.max_body_size.as_u64() as usize)
         .await
         .map_err(|e| {
-            if let Some(src) = std::error::Error::source(&e) {
-                if src.is::<http_body_util::LengthLimitError>() {
-                    return AppError::PayloadTooLarge(e.to_string());
-                }
+            if let Some(src) = std::error::Error::source(&e)
+                && src.is::<http_body_util::LengthLimitError>()
+            {
+                return AppError::PayloadTooLarge(e.to_string());
             }
 
             AppError::Internal(e.into())
         })?;
-    let msg = decode_raw_body(&content_type.into(), &raw)?;
-    let pub_req = PubReq::new(req, msg);
-
-    if let Some(pub_req) = state.script.publish(pub_req).await? {
-        let subs = state.broadcast.send(pub_req).unwrap_or(0);
-
-        Ok((
-            StatusCode::ACCEPTED,
-            Json(json!({
-                "subscribers": subs,
-                "queued": state.broadcast.len(),
-            })),
-        ))
-    } else {
-        Ok((
-            StatusCode::FORBIDDEN,
-            Json(json!({"error": "message rejected by script"})),
-        ))
+
+    let pub_msgs = match decode_raw_body(&content_type.into(), &raw)? {
+        PubBody::One(pub_msg) => {
+            let pub_req = PubReq::new(req, pub_msg.msg, [topics, pub_msg.topics].concat());
+
+            let pub_req = state.script.publish(pub_req).await.inspect_err(|_| {
+                state.metrics.publish("error");
+            })?;
+
+            return if let Some(pub_req) = pub_req {
+                state.metrics.publish("accepted");
+                let (subs, queued) = state.broadcast(pub_req).await;
+
+                Ok((
+                    StatusCode::ACCEPTED,
+                    Json(json!({
+                        "subscribers": subs,
+                        "queued": queued,
+                    })),
+                ))
+            } else {
+                state.metrics.publish("rejected");
+                Ok((
+                    StatusCode::FORBIDDEN,
+                    Json(json!({"error": "message rejected by script"})),
+                ))
+            };
+        }
+        PubBody::Batch(pub_msgs) => pub_msgs,
+    };
+
+    // Publish the batch in order, rejecting messages individually
+    let mut results = Vec::with_capacity(pub_msgs.len());
+    let mut total_subs = 0;
+    let mut total_queued = 0;
+    let mut accepted = 0;
+
+    for pub_msg in pub_msgs {
+        let pub_req = match pub_msg {
+            Ok(pub_msg) => PubReq::new(
+                req.clone(),
+                pub_msg.msg,
+                [topics.as_slice(), &pub_msg.topics].concat(),
+            ),
+            Err(e) => {
+                results.push(json!({"accepted": false, "error": e}));
+                continue;
+            }
+        };
+
+        match state.script.publish(pub_req).await {
+            Ok(Some(pub_req)) => {
+                state.metrics.publish("accepted");
+                let (subs, queued) = state.broadcast(pub_req).await;
+                total_subs += subs;
+                total_queued = total_queued.max(queued);
+                accepted += 1;
+                results.push(json!({"accepted": true, "subscribers": subs}));
+            }
+            Ok(None) => {
+                state.metrics.publish("rejected");
+                results.push(json!({"accepted": false, "error": "message rejected by script"}));
+            }
+            Err(e) => {
+                tracing::error!("{e}");
+                state.metrics.publish("error");
+                results.push(json!({"accepted": false, "error": "script error"}));
+            }
+        }
     }
+
+    // A batch of messages that were all rejected fails as a whole
+    let status = if accepted == 0 && !results.is_empty() {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::ACCEPTED
+    };
+
+    Ok((
+        status,
+        Json(json!({
+            "subscribers": total_subs,
+            "queued": total_queued,
+            "accepted": accepted,
+            "rejected": results.len() - accepted,
+            "messages": results,
+        })),
+    ))
+}
+
+/// Responds with the server metrics in the Prometheus text format.
+async fn metrics(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let queue_depth = state.broadcast.len() + state.topics.lens().values().sum::<usize>();
+    let text = state
+        .metrics
+        .encode(queue_depth)
+        .map_err(|e| AppError::Internal(e.into()))?;
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        text,
+    ))
 }
 
 #[derive(Debug, serde::Deserialize)]
-struct LastEventIdQuery {
+struct SubscribeQuery {
     last_event_id: Option<String>,
+    /// The history position to catch-up a long-poll from
+    cursor: Option<String>,
+    #[serde(default)]
+    topic: Vec<String>,
+    transport: Option<String>,
 }
 
 #[debug_handler]
 async fn subscribe(
     State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Query(LastEventIdQuery { last_event_id }): Query<LastEventIdQuery>,
+    ConnectInfo(addr): ConnectInfo<Addr>,
+    Query(SubscribeQuery {
+        last_event_id,
+        cursor,
+        topic: topics,
+        transport,
+    }): Query<SubscribeQuery>,
+    ws: Option<WebSocketUpgrade>,
     axum_req: axum::extract::Request,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
     // Header takes precedence over query parameter
     let last_event_id = axum_req
         .headers()
@@ -125,11 +345,46 @@ async fn subscribe(
         .and_then(|id| id.to_str().ok().map(String::from))
         .or(last_event_id);
 
+    // Long-polling is selected with `?transport=poll`, or by accepting JSON
+    // but not an event stream.
+    let poll = match transport.as_deref() {
+        Some(transport) => transport == "poll",
+        None => axum_req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| {
+                accept.contains(mime::APPLICATION_JSON.essence_str())
+                    && !accept.contains(mime::TEXT_EVENT_STREAM.essence_str())
+            }),
+    };
+
+    // Without a history or a `catchup` function, the messages published
+    // between polls would be lost.
+    if poll && !state.history.is_enabled() && !state.script.defines("catchup") {
+        return Err(AppError::BadRequest(
+            "long-polling needs the message history or a catchup function".into(),
+        ));
+    }
+
+    let cursor = cursor
+        .map(|cursor| cursor.parse::<Position>())
+        .transpose()
+        .map_err(AppError::BadRequest)?;
+
     let req = Req::new(addr, &axum_req);
-    let sub_req = SubReq::new(req);
+    let sub_req = SubReq::new(req, topics);
 
     match state.script.subscribe(sub_req).await? {
-        Some(sub_req) => Ok(sse_subscribe(state, sub_req, last_event_id).await),
+        Some(sub_req) => match ws {
+            Some(ws) => Ok(ws_subscribe(state, sub_req, last_event_id, ws)),
+            None if poll => Ok(poll_subscribe(state, sub_req, last_event_id, cursor)
+                .await
+                .into_response()),
+            None => Ok(sse_subscribe(state, sub_req, last_event_id)
+                .await
+                .into_response()),
+        },
         None => Err(AppError::Forbidden("subscribe rejected by script".into())),
     }
 }
@@ -139,55 +394,257 @@ async fn sse_subscribe(
     sub_req: SubReq,
     last_event_id: Option<String>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let start = Instant::now();
     let keep_alive = KeepAlive::new()
         .interval(state.keep_alive)
         .text(state.keep_alive_text.clone());
+    let keep_alive_enabled = !state.keep_alive.is_zero();
+    let registration = state.subscribers.register(&sub_req);
+    let stats = registration.stats();
+    let msgs = subscription(state, sub_req, last_event_id, registration).await;
 
-    let catchup_msgs = state
-        .script
-        .catchup(&sub_req, last_event_id)
-        .await
-        .ok()
-        .flatten()
-        .unwrap_or_default();
-    let catchup_stream = stream::iter(catchup_msgs.into_iter().filter_map(|msg| {
-        if !msg.is_empty() {
-            Some(Ok(msg.into()))
-        } else {
-            None
-        }
+    let sse = Sse::new(msgs.map(move |msg| {
+        stats.sent(msg.event_stream_len());
+        Ok(msg.into())
     }));
 
-    let events = async_stream::stream! {
-        let event_stream = stream::once(async { Ok(Event::default().comment("ok")) }).chain(catchup_stream).chain(
-            BroadcastStream::new(state.broadcast.subscribe()).filter_map(async |pub_req| { match pub_req {
-                Ok(pub_req) if !pub_req.msg().is_empty() => {
-                    match state.script.message(pub_req, &sub_req).await {
-                        Ok(Some(pub_req)) if !pub_req.msg().is_empty() => {
-                            Some(Ok(pub_req.msg().clone().into()))
-                        },
-                        Ok(_) => {
-                            tracing::debug!("received empty message from script");
-                            None
-                        },
+    // A zero interval disables the keep-alive messages
+    if keep_alive_enabled {
+        sse.keep_alive(keep_alive)
+    } else {
+        sse
+    }
+}
+
+/// Subscribes over a WebSocket connection.
+///
+/// Each message is sent as a JSON text frame with the same fields as a
+/// published message.  Keep-alives are sent as ping frames, and the
+/// connection is closed after a timeout (or lag) message.
+fn ws_subscribe(
+    state: AppState,
+    sub_req: SubReq,
+    last_event_id: Option<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(async move |mut socket| {
+        let keep_alive_text = state.keep_alive_text.clone();
+        let keep_alive_enabled = !state.keep_alive.is_zero();
+        // A zero interval disables the keep-alive pings
+        let mut keep_alive = tokio::time::interval(state.keep_alive.max(Duration::from_secs(1)));
+        keep_alive.tick().await;
+
+        let registration = state.subscribers.register(&sub_req);
+        let stats = registration.stats();
+        let msgs = subscription(state, sub_req, last_event_id, registration).await;
+        tokio::pin!(msgs);
+
+        loop {
+            tokio::select! {
+                msg = msgs.next() => {
+                    let Some(msg) = msg else {
+                        let _ = socket.send(ws::Message::Close(None)).await;
+                        break;
+                    };
+
+                    let text = match serde_json::to_string(&msg) {
+                        Ok(text) => text,
                         Err(e) => {
                             tracing::error!("{e}");
-                            None
+                            continue;
                         }
+                    };
+
+                    let len = text.len();
+
+                    if socket.send(ws::Message::Text(text)).await.is_err() {
+                        break;
                     }
+
+                    stats.sent(len);
                 },
-                Ok(_) => {
-                    tracing::debug!("received empty message");
-                    None
-                }
-                Err(e) => {
-                    tracing::error!("{e}");
-                    None
+                frame = socket.recv() => match frame {
+                    // Messages from the client are ignored
+                    Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {},
+                },
+                _ = keep_alive.tick(), if keep_alive_enabled => {
+                    let ping = ws::Message::Ping(keep_alive_text.clone().into_bytes());
+
+                    if socket.send(ping).await.is_err() {
+                        break;
+                    }
                 }
-            }}),
-        );
-        tokio::pin!(event_stream);
+            }
+        }
+
+        // The subscription is dropped here. Unsubscribe called.
+    })
+}
+
+/// Subscribes with a long-poll.
+///
+/// Responds with the messages the subscriber is caught-up with or, if there
+/// are none, waits until at least one live message arrives, the poll times
+/// out, or the server shuts down.  The response includes the id of the last
+/// message, and the position in the history that the poll covered, as the
+/// cursors for the next poll.
+async fn poll_subscribe(
+    state: AppState,
+    sub_req: SubReq,
+    last_event_id: Option<String>,
+    cursor: Option<Position>,
+) -> Json<serde_json::Value> {
+    // Unsubscribe on guard drop, even if the client leaves during catch-up
+    let _guard = SubReqGuard::new(&state, sub_req.clone());
+
+    let mut live_stream = live_stream(&state, &sub_req);
+    let mut position = state.history.last_position().await;
+
+    let Catchup {
+        msgs,
+        ids: mut catchup_ids,
+        position: catchup_position,
+    } = catchup(
+        &state,
+        &sub_req,
+        last_event_id.clone(),
+        cursor,
+        &mut live_stream,
+    )
+    .await;
+    let mut msgs: Vec<Msg> = msgs.into_iter().filter(|msg| !msg.is_empty()).collect();
+    position = position.max(catchup_position);
+
+    if msgs.is_empty() {
+        let timeout = tokio::time::sleep(state.poll_timeout);
+        tokio::pin!(timeout);
+
+        // Whether the poll is in step with the live messages, until it lags
+        let mut in_step = true;
+
+        while in_step && msgs.is_empty() {
+            tokio::select! {
+                pub_req = live_stream.next() => match pub_req {
+                    Some(pub_req) => {
+                        in_step = poll_message(&state, &sub_req, pub_req, &mut catchup_ids, &mut position, &mut msgs).await;
+                    }
+                    None => break,
+                },
+                _ = &mut timeout => break,
+                _ = state.shutdown.cancelled() => break,
+            }
+        }
+
+        // Include the messages that arrived at the same time
+        while in_step && let Some(Some(pub_req)) = live_stream.next().now_or_never() {
+            in_step = poll_message(
+                &state,
+                &sub_req,
+                pub_req,
+                &mut catchup_ids,
+                &mut position,
+                &mut msgs,
+            )
+            .await;
+        }
+    }
+
+    let last_event_id = msgs
+        .iter()
+        .rev()
+        .find_map(|msg| msg.id.clone())
+        .or(last_event_id);
+
+    Json(json!({
+        "messages": msgs,
+        "last_event_id": last_event_id,
+        "cursor": position.map(|position| position.to_string()),
+    }))
+}
+
+/// Adds a live message to the messages of a long-poll, and advances its
+/// history position past it.
+///
+/// Returns `false` if the subscriber lagged, to end the poll before the
+/// messages after the lag.  The next poll catches-up from the history, or
+/// without a history the subscriber is sent a `lagged` event.
+async fn poll_message(
+    state: &AppState,
+    sub_req: &SubReq,
+    pub_req: Result<PubReq, BroadcastStreamRecvError>,
+    catchup_ids: &mut CatchupIds,
+    position: &mut Option<Position>,
+    msgs: &mut Vec<Msg>,
+) -> bool {
+    match pub_req {
+        Ok(pub_req) => {
+            *position = (*position).max(pub_req.position());
+
+            if !catchup_ids.is_caught_up(&pub_req, sub_req) {
+                msgs.extend(message(state, pub_req, sub_req).await);
+            }
+
+            true
+        }
+        Err(BroadcastStreamRecvError::Lagged(missed)) => {
+            tracing::warn!("subscriber lagged behind by {missed} messages");
+            state.metrics.lagged(missed);
+
+            if position.is_none() {
+                msgs.push(lagged(missed));
+            }
+
+            false
+        }
+    }
+}
+
+/// The stream of messages for a subscriber, independent of the transport.
+///
+/// The subscriber is caught-up first and then receives live messages, and
+/// the messages the script sends through the registration of the transport,
+/// until it times out, is disconnected or the server shuts down.  Comments
+/// and retry delays generated by the server (such as the initial `ok`
+/// comment) are part of the stream as messages.
+async fn subscription(
+    state: AppState,
+    sub_req: SubReq,
+    last_event_id: Option<String>,
+    mut control: Registration,
+) -> impl Stream<Item = Msg> {
+    let start = Instant::now();
+
+    // Unsubscribe on guard drop, even if the client leaves during catch-up
+    let guard = SubReqGuard::new(&state, sub_req.clone());
+
+    // Subscribe to live messages before catching-up so that messages
+    // published in the meantime are not lost.
+    let mut live_stream = live_stream(&state, &sub_req);
+    let caught_up = catchup(
+        &state,
+        &sub_req,
+        last_event_id.clone(),
+        None,
+        &mut live_stream,
+    )
+    .await;
+
+    async_stream::stream! {
+        let _guard = guard;
+
+        // The id of the last message delivered, to catch-up from after lagging
+        let mut last_event_id = last_event_id;
+        let mut catchup_ids = caught_up.ids;
+
+        yield comment("ok", None);
+
+        for msg in caught_up.msgs.into_iter().filter(|msg| !msg.is_empty()) {
+            if msg.id.is_some() {
+                last_event_id = msg.id.clone();
+            }
+
+            yield msg;
+        }
 
         let timeout = if state.timeout.as_millis() > 0 {
             tokio::time::sleep(state.timeout)
@@ -197,15 +654,62 @@ async fn sse_subscribe(
         };
         tokio::pin!(timeout);
 
-        // Unsubscribe on guard drop
-        let _guard = SubReqGuard::new(&state, sub_req.clone());
-
         loop {
             tokio::select! {
-                event = event_stream.next() => {
-                    if let Some(event) = event {
-                        yield event;
-                    }
+                pub_req = live_stream.next() => match pub_req {
+                    Some(Ok(pub_req)) => {
+                        if catchup_ids.is_caught_up(&pub_req, &sub_req) {
+                            continue;
+                        }
+
+                        if let Some(msg) = message(&state, pub_req, &sub_req).await {
+                            if msg.id.is_some() {
+                                last_event_id = msg.id.clone();
+                            }
+
+                            yield msg;
+                        }
+                    },
+                    Some(Err(BroadcastStreamRecvError::Lagged(missed))) => {
+                        tracing::warn!("subscriber lagged behind by {missed} messages");
+                        state.metrics.lagged(missed);
+
+                        match lag_policy(&state, &sub_req, missed, last_event_id.as_deref()).await {
+                            LagPolicy::Ignore => {},
+                            LagPolicy::Notify => yield lagged(missed),
+                            LagPolicy::Catchup => {
+                                let caught_up = catchup(&state, &sub_req, last_event_id.clone(), None, &mut live_stream).await;
+                                catchup_ids = caught_up.ids;
+
+                                for msg in caught_up.msgs.into_iter().filter(|msg| !msg.is_empty()) {
+                                    if msg.id.is_some() {
+                                        last_event_id = msg.id.clone();
+                                    }
+
+                                    yield msg;
+                                }
+                            },
+                            LagPolicy::Disconnect => {
+                                yield comment("lagged", Some(state.timeout_retry));
+                                break;
+                            },
+                        }
+                    },
+                    None => break,
+                },
+                Some(control) = control.recv() => match control {
+                    Control::Send(msg) if msg.is_empty() => {},
+                    Control::Send(msg) => {
+                        if msg.id.is_some() {
+                            last_event_id = msg.id.clone();
+                        }
+
+                        yield msg;
+                    },
+                    Control::Disconnect(retry) => {
+                        yield comment("disconnect", Some(retry));
+                        break;
+                    },
                 },
                 _ = &mut timeout => {
                     let retry = match state.script.timeout(&sub_req, &start.elapsed()).await {
@@ -217,14 +721,470 @@ async fn sse_subscribe(
                         }
                     };
 
-                    yield Ok(Event::default().comment("timeout").retry(retry));
+                    yield comment("timeout", Some(retry));
+                    break;
+                }
+                _ = state.shutdown.cancelled() => {
+                    yield comment("shutdown", Some(shutdown_retry(&state)));
                     break;
                 }
             }
         }
 
         // _guard dropped here. Unsubscribe called.
+    }
+}
+
+/// A message with just a comment and an optional retry delay.
+fn comment(text: &str, retry: Option<Duration>) -> Msg {
+    Msg {
+        comment: Some(vec![text.to_string()]),
+        retry: retry.map(|retry| retry.as_millis() as u64),
+        ..Default::default()
+    }
+}
+
+/// The `lagged` event sent to a subscriber with the number of messages it
+/// missed.
+fn lagged(missed: u64) -> Msg {
+    Msg {
+        event: Some("lagged".into()),
+        data: Some(missed.to_string()),
+        ..Default::default()
+    }
+}
+
+/// The retry delay for a subscriber disconnected by a shutdown, with a
+/// random jitter so that the subscribers do not all reconnect at once.
+fn shutdown_retry(state: &AppState) -> Duration {
+    let jitter = rand::random_range(0..=state.shutdown_retry_jitter.as_millis() as u64);
+    state.shutdown_retry + Duration::from_millis(jitter)
+}
+
+/// Passes a live message through the `message(pub, sub)` script function.
+///
+/// Returns the message to deliver to the subscriber, if any.
+async fn message(state: &AppState, pub_req: PubReq, sub_req: &SubReq) -> Option<Msg> {
+    if pub_req.msg().is_empty() {
+        tracing::debug!("received empty message");
+        return None;
+    }
+
+    match state.script.message(pub_req, sub_req).await {
+        Ok(Some(pub_req)) if !pub_req.msg().is_empty() => {
+            state.metrics.message("delivered");
+            Some(pub_req.msg().clone())
+        }
+        Ok(_) => {
+            tracing::debug!("received empty message from script");
+            state.metrics.message("filtered");
+            None
+        }
+        Err(e) => {
+            tracing::error!("{e}");
+            state.metrics.message("error");
+            None
+        }
+    }
+}
+
+/// The ids of the messages a catch-up by the script delivered, to skip them
+/// if they are also received live.
+///
+/// The live stream merges one stream per topic, each in publish order, so
+/// the ids are only given up on for a topic stream once a message that was
+/// not caught-up arrives on it.
+#[derive(Debug, Default)]
+struct CatchupIds {
+    ids: HashSet<String>,
+    /// The topic streams past the caught-up messages, by the topic they are
+    /// taken from, or `None` for the stream of a subscriber without topics.
+    passed: HashSet<Option<String>>,
+}
+
+impl CatchupIds {
+    fn new(ids: HashSet<String>) -> Self {
+        Self {
+            ids,
+            passed: HashSet::new(),
+        }
+    }
+
+    /// Whether a live message was already delivered by the catch-up.
+    fn is_caught_up(&mut self, pub_req: &PubReq, sub_req: &SubReq) -> bool {
+        if self.ids.is_empty() {
+            return false;
+        }
+
+        // The topic stream the message is taken from, as in `live_stream`
+        let topic = pub_req
+            .topics()
+            .iter()
+            .find(|topic| sub_req.topics().contains(topic))
+            .cloned();
+
+        if self.passed.contains(&topic) {
+            return false;
+        }
+
+        match &pub_req.msg().id {
+            Some(id) if self.ids.remove(id) => true,
+            _ => {
+                self.passed.insert(topic);
+                false
+            }
+        }
+    }
+}
+
+/// Decides what to do with a subscriber that lagged behind the message queue.
+///
+/// The `lagged(sub, missed)` script function takes precedence over the
+/// `--lag-policy` option.  A subscriber that cannot be caught-up, because
+/// it has not received a message with an id yet or there is neither a history
+/// nor a `catchup` function, is notified instead.
+async fn lag_policy(
+    state: &AppState,
+    sub_req: &SubReq,
+    missed: u64,
+    last_event_id: Option<&str>,
+) -> LagPolicy {
+    let policy = match state.script.lagged(sub_req, missed).await {
+        Ok(Some(policy)) => LagPolicy::from_str(&policy, true).unwrap_or_else(|e| {
+            tracing::error!("invalid lag policy from script: {e}");
+            state.lag_policy
+        }),
+        Ok(None) => state.lag_policy,
+        Err(e) => {
+            tracing::error!("{e}");
+            state.lag_policy
+        }
+    };
+
+    let can_catchup =
+        last_event_id.is_some() && (state.history.is_enabled() || state.script.defines("catchup"));
+
+    if policy == LagPolicy::Catchup && !can_catchup {
+        tracing::warn!(
+            "cannot catch-up lagged subscriber without a Last-Event-ID and a history or `catchup` function, notifying it instead"
+        );
+        return LagPolicy::Notify;
+    }
+
+    policy
+}
+
+/// The messages to catch-up a subscriber with.
+struct Catchup {
+    msgs: Vec<Msg>,
+    /// The ids of the messages the catch-up covers, used to skip them if they
+    /// are also received live.
+    ids: CatchupIds,
+    /// The position of the last message of the history the catch-up covers
+    position: Option<Position>,
+}
+
+/// Collects the messages to catch-up a subscriber with.
+///
+/// The `catchup(sub, last_event_id)` script function takes precedence.  If it
+/// returns `nil`, then the subscriber is caught-up from the built-in history
+/// (if enabled), with each message passed through `message(pub, sub)` as if it
+/// were delivered live.
+///
+/// The history is read under the publish lock and the live stream replaced
+/// with a new one at the same time, so that every message is either in the
+/// catch-up or received live, but never both.  A history `cursor` from a
+/// long-poll takes precedence over the last event id to read it from.
+async fn catchup(
+    state: &AppState,
+    sub_req: &SubReq,
+    last_event_id: Option<String>,
+    cursor: Option<Position>,
+    live: &mut LiveStream,
+) -> Catchup {
+    let mut catchup = Catchup {
+        msgs: Vec::new(),
+        ids: CatchupIds::default(),
+        position: None,
+    };
+
+    match state.script.catchup(sub_req, last_event_id.clone()).await {
+        Ok(Some(msgs)) => {
+            state.metrics.catchup("script", msgs.len());
+            catchup.ids = CatchupIds::new(msgs.iter().filter_map(|msg| msg.id.clone()).collect());
+            catchup.msgs = msgs;
+            return catchup;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("{e}");
+            return catchup;
+        }
+    }
+
+    let order = state.publish_lock.lock().await;
+
+    let pub_reqs = match cursor {
+        Some(cursor) => state.history.after_position(sub_req, cursor).await,
+        None => state.history.after(sub_req, last_event_id.as_deref()).await,
+    };
+
+    let pub_reqs = match pub_reqs {
+        Ok(Some(pub_reqs)) => {
+            *live = live_stream(state, sub_req);
+            drop(order);
+            pub_reqs
+        }
+        Ok(None) => return catchup,
+        Err(e) => {
+            tracing::error!("{e}");
+            return catchup;
+        }
     };
 
-    Sse::new(events).keep_alive(keep_alive)
+    catchup.position = pub_reqs.iter().filter_map(PubReq::position).max();
+
+    for pub_req in pub_reqs {
+        if let Some(msg) = message(state, pub_req, sub_req).await {
+            catchup.msgs.push(msg);
+        }
+    }
+
+    state.metrics.catchup("history", catchup.msgs.len());
+
+    catchup
+}
+
+/// A stream of live publish requests.
+type LiveStream = BoxStream<'static, Result<PubReq, BroadcastStreamRecvError>>;
+
+/// The stream of live publish requests for a subscriber.
+///
+/// Subscribers without topics receive the messages published without a
+/// topic.  Subscribers with topics receive the messages published to any
+/// of them, merged into a single stream.  A message published to several
+/// topics is only taken from the first of its topics that the subscriber
+/// is subscribed to, so it is never delivered twice.
+fn live_stream(state: &AppState, sub_req: &SubReq) -> LiveStream {
+    if sub_req.topics().is_empty() {
+        return BroadcastStream::new(state.broadcast.subscribe()).boxed();
+    }
+
+    let sub_topics: Arc<HashSet<String>> = Arc::new(sub_req.topics().iter().cloned().collect());
+
+    stream::select_all(sub_req.topics().iter().map(|topic| {
+        let topic = topic.clone();
+        let sub_topics = sub_topics.clone();
+
+        state
+            .topics
+            .subscribe(&topic)
+            .filter(move |pub_req| {
+                let keep = match pub_req {
+                    Ok(pub_req) => pub_req
+                        .topics()
+                        .iter()
+                        .find(|t| sub_topics.contains(*t))
+                        .is_some_and(|t| *t == topic),
+                    Err(_) => true,
+                };
+
+                future::ready(keep)
+            })
+            .boxed()
+    }))
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt as _;
+
+    fn pub_req(id: &str, topic: &str) -> PubReq {
+        let msg = Msg {
+            id: Some(id.to_string()),
+            data: Some(id.to_string()),
+            ..Default::default()
+        };
+
+        PubReq::new(Req::script("/pub"), msg, vec![topic.to_string()])
+    }
+
+    fn pub_req_without_topic(id: &str) -> PubReq {
+        let msg = Msg {
+            id: Some(id.to_string()),
+            ..Default::default()
+        };
+
+        PubReq::new(Req::script("/pub"), msg, vec![])
+    }
+
+    #[test]
+    fn catchup_ids_are_kept_per_topic_stream() {
+        let sub_req = SubReq::new(Req::script("/sub"), vec!["a".into(), "b".into()]);
+        let mut ids = CatchupIds::new(["a1".to_string(), "a2".to_string()].into());
+
+        // A message on topic b arrives before the caught-up ones on topic a
+        assert!(!ids.is_caught_up(&pub_req("b1", "b"), &sub_req));
+        assert!(ids.is_caught_up(&pub_req("a1", "a"), &sub_req));
+        assert!(!ids.is_caught_up(&pub_req("a3", "a"), &sub_req));
+
+        // Topic a is past the catch-up, so a2 is no longer expected on it
+        assert!(!ids.is_caught_up(&pub_req("a2", "a"), &sub_req));
+    }
+
+    #[test]
+    fn catchup_ids_without_topics() {
+        let sub_req = SubReq::new(Req::script("/sub"), vec![]);
+        let mut ids = CatchupIds::new(["1".to_string()].into());
+
+        assert!(ids.is_caught_up(&pub_req_without_topic("1"), &sub_req));
+        assert!(!ids.is_caught_up(&pub_req_without_topic("1"), &sub_req));
+    }
+
+    fn decode(mime: &str, body: &str) -> Result<PubBody, AppError> {
+        decode_raw_body(&mime.parse().unwrap(), &body::Bytes::from(body.to_string()))
+    }
+
+    /// The data and topics of a decoded message, or its error.
+    type Decoded = Result<(Option<String>, Vec<String>), String>;
+
+    fn batch(pub_body: Result<PubBody, AppError>) -> Vec<Decoded> {
+        match pub_body {
+            Ok(PubBody::Batch(pub_msgs)) => pub_msgs
+                .into_iter()
+                .map(|pub_msg| pub_msg.map(|pub_msg| (pub_msg.msg.data, pub_msg.topics)))
+                .collect(),
+            _ => panic!("expected a batch"),
+        }
+    }
+
+    #[test]
+    fn decode_json_message_with_topics() {
+        let Ok(PubBody::One(pub_msg)) = decode("application/json", r#"{"data":"x","topic":"a"}"#)
+        else {
+            panic!("expected a single message");
+        };
+        assert_eq!(pub_msg.msg.data.as_deref(), Some("x"));
+        assert_eq!(pub_msg.topics, ["a"]);
+
+        let Ok(PubBody::One(pub_msg)) =
+            decode("application/json", r#"{"data":"x","topic":["a","b"]}"#)
+        else {
+            panic!("expected a single message");
+        };
+        assert_eq!(pub_msg.topics, ["a", "b"]);
+    }
+
+    #[test]
+    fn decode_invalid_json_message() {
+        for body in ["5", r#""x""#, r#"{"data":"x","topic":5}"#, "{"] {
+            let Err(AppError::BadRequest(e)) = decode("application/json", body) else {
+                panic!("expected a bad request for {body:?}");
+            };
+            assert!(!e.contains("JsonMsg") && !e.contains("struct"), "{e}");
+        }
+    }
+
+    #[test]
+    fn decode_json_array() {
+        let results = batch(decode(
+            "application/json",
+            r#"[{"data":"1"}, 5, {"data":"3","topic":"a"}, []]"#,
+        ));
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0], Ok((Some("1".to_string()), vec![])));
+        assert_eq!(
+            results[2],
+            Ok((Some("3".to_string()), vec!["a".to_string()]))
+        );
+        for e in [&results[1], &results[3]] {
+            let e = e.as_ref().unwrap_err();
+            assert!(e.contains("expected a message object"), "{e}");
+            assert!(!e.contains("JsonMsg"), "{e}");
+        }
+    }
+
+    #[test]
+    fn decode_ndjson() {
+        let results = batch(decode(
+            "application/x-ndjson",
+            "{\"data\":\"1\"}\n\n  \n{oops}\n{\"data\":\"3\",\"topic\":[\"a\"]}\n",
+        ));
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], Ok((Some("1".to_string()), vec![])));
+        assert!(results[1].is_err());
+        assert_eq!(
+            results[2],
+            Ok((Some("3".to_string()), vec!["a".to_string()]))
+        );
+    }
+
+    #[test]
+    fn decode_unsupported_media_type() {
+        assert!(matches!(
+            decode("text/plain", "data"),
+            Err(AppError::UnsupportedMediaType(_))
+        ));
+    }
+
+    async fn post_batch(
+        state: &AppState,
+        mime: &str,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut request = axum::http::Request::post(&state.pub_path)
+            .header(axum::http::header::CONTENT_TYPE, mime)
+            .body(axum::body::Body::from(body.to_string()))
+            .unwrap();
+        let addr: std::net::SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(Addr::from(addr)));
+
+        let response = router(state)
+            .with_state(state.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publish_batch_results() {
+        let cli = <crate::cli::Cli as clap::Parser>::parse_from(["tinysse"]);
+        let state = AppState::from_cli(&cli).await.unwrap();
+
+        let (status, json) = post_batch(
+            &state,
+            "application/json",
+            r#"[{"data":"1"}, 5, {"data":"3"}]"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(json["accepted"], 2);
+        assert_eq!(json["rejected"], 1);
+        assert_eq!(json["messages"][0]["accepted"], true);
+        assert_eq!(json["messages"][1]["accepted"], false);
+        assert_eq!(json["messages"][2]["accepted"], true);
+
+        // A batch with nothing accepted fails as a whole
+        let (status, json) = post_batch(&state, "application/x-ndjson", "5\n{oops}\n").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json["accepted"], 0);
+        assert_eq!(json["rejected"], 2);
+
+        // An empty batch is not a failure
+        let (status, json) = post_batch(&state, "application/json", "[]").await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(json["accepted"], 0);
+    }
 }